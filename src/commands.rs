@@ -1,6 +1,8 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+use crate::utils::{compression::Compression, lock::LockMode, text_diff::MergeStrategy};
+
 #[derive(Parser)]
 #[command(name = "denali", about = "Denali CLI tool")]
 pub struct Cli {
@@ -9,6 +11,14 @@ pub struct Cli {
 
     #[arg(long, short, global = true)]
     pub root: Option<PathBuf>,
+
+    /// Seconds to wait for another denali process to release the store
+    #[arg(long, global = true)]
+    pub wait: Option<u64>,
+
+    /// Worker threads used to hash, compress and restore files, defaults to the number of CPUs
+    #[arg(long, short, global = true)]
+    pub jobs: Option<usize>,
 }
 
 #[derive(Subcommand)]
@@ -39,6 +49,8 @@ pub enum Commands {
         with_config: bool,
         #[arg(long, short)]
         wipe: bool,
+        #[arg(long, short)]
+        incremental: bool,
     },
     Check {
         #[arg(long, short)]
@@ -54,6 +66,40 @@ pub enum Commands {
         #[arg(long, short)]
         dry: bool,
     },
+    Repack {
+        #[arg(long, short)]
+        all: bool,
+    },
+    Fsck,
+    Migrate,
+    TrainDict {
+        project: String,
+    },
+    /// Show or set how new objects of the store are compressed
+    Compression {
+        /// `zstd`, `zstd:<level>` or `none`
+        value: Option<Compression>,
+        /// Stop compressing new objects of this project with its trained dictionary
+        #[arg(long = "no-dictionary")]
+        no_dictionary: Option<String>,
+    },
+    Prune {
+        project: String,
+        #[arg(long = "keep-last", default_value_t = 0)]
+        keep_last: usize,
+        #[arg(long = "keep-hourly", default_value_t = 0)]
+        keep_hourly: usize,
+        #[arg(long = "keep-daily", default_value_t = 0)]
+        keep_daily: usize,
+        #[arg(long = "keep-weekly", default_value_t = 0)]
+        keep_weekly: usize,
+        #[arg(long = "keep-monthly", default_value_t = 0)]
+        keep_monthly: usize,
+        #[arg(long = "dry-run", short)]
+        dry: bool,
+        #[arg(long, short)]
+        clean: bool,
+    },
     Tmpl {
         #[command(subcommand)]
         sub: TmplCommand,
@@ -66,6 +112,38 @@ pub enum Commands {
     List {
         project: String,
     },
+    Diff {
+        project: String,
+        from: String,
+        to: Option<String>,
+        #[arg(long)]
+        patch: bool,
+    },
+    Status {
+        project: String,
+        #[arg(long)]
+        patch: bool,
+    },
+    Stats {
+        project: Option<String>,
+        #[arg(long)]
+        json: bool,
+    },
+    Merge {
+        project: String,
+        base: String,
+        ours: String,
+        theirs: String,
+        #[arg(long, short)]
+        name: Option<String>,
+        #[arg(long, short, value_enum)]
+        strategy: Option<MergeStrategy>,
+        #[arg(long, short)]
+        path: Option<PathBuf>,
+        /// Merge even if the cell has unsaved changes, which are overwritten
+        #[arg(long, short)]
+        force: bool,
+    },
     Sync {
         project: String,
         remote: String,
@@ -76,13 +154,57 @@ pub enum Commands {
     },
 }
 
+impl Commands {
+    /// Lock needed on the store for the command to run.
+    pub fn lock_mode(&self) -> LockMode {
+        match self {
+            Commands::Load { .. }
+            | Commands::Fsck
+            | Commands::Copy { .. }
+            | Commands::List { .. }
+            | Commands::Diff { .. }
+            | Commands::Status { .. }
+            | Commands::Stats { .. }
+            | Commands::Compression {
+                value: None,
+                no_dictionary: None,
+            }
+            | Commands::Clean { dry: true }
+            | Commands::Prune { dry: true, .. }
+            | Commands::Tmpl {
+                sub: TmplCommand::Apply { .. } | TmplCommand::List,
+            }
+            | Commands::Remote {
+                sub: RemoteCommands::Send { .. } | RemoteCommands::Manifest { .. },
+            } => LockMode::Shared,
+            _ => LockMode::Exclusive,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum RemoteCommands {
-    Receive,
-    Send,
-    Manifest { name: String },
-    Add { name: String, host: String },
-    Remove { name: String },
+    Receive {
+        /// Object format of the store on the other end, older peers do not pass it
+        #[arg(long, default_value_t = 1)]
+        object_format: u32,
+    },
+    Send {
+        #[arg(long, default_value_t = 1)]
+        object_format: u32,
+    },
+    Manifest {
+        name: String,
+        #[arg(long, default_value_t = 1)]
+        object_format: u32,
+    },
+    Add {
+        name: String,
+        host: String,
+    },
+    Remove {
+        name: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -10,7 +10,7 @@ use uuid::Uuid;
 
 use crate::utils::{
     CellConfig, CellRef, DenaliToml, Errors, MainManifest, ProjectManifest, ProjectRef,
-    context::AppContext,
+    atomic::write_atomic, context::AppContext,
 };
 
 pub fn check(ctx: &AppContext, path: Option<&Path>) -> Result<(), Errors> {
@@ -22,6 +22,7 @@ pub fn check(ctx: &AppContext, path: Option<&Path>) -> Result<(), Errors> {
     ctx.make_root_dir()?;
 
     let config = read_config(&root)?;
+    check_retention(&config)?;
     let manifest = ctx.load_main_manifest()?;
     if !manifest.projects.contains_key(&config.root.name) {
         let mut try_key: Option<String> = None;
@@ -83,6 +84,9 @@ fn maybe_delete(
             lock: String::new(),
             snapshot_before: String::new(),
             snapshot_after: String::new(),
+            retention: None,
+            compression: None,
+            delta: None,
         };
         update_project_config(
             &Path::new(&project_manifest.source),
@@ -120,7 +124,7 @@ fn delete_cell(ctx: &AppContext, cell: String, project_name: String) -> Result<(
         .is_deleted = true;
     ctx.write_project_manifest(uuid, &project_manifest)?;
     let manifest_data = serde_json::to_vec_pretty(&manifest)?;
-    fs::write(ctx.main_manifest_path(), &manifest_data)?;
+    write_atomic(&ctx.main_manifest_path(), &manifest_data)?;
     Ok(())
 }
 
@@ -360,7 +364,7 @@ fn update_cell_name(
             .insert(new_name.to_string(), cell_ref);
         let proj_manifest_data = serde_json::to_vec_pretty(&project_manifest)?;
         let file_path = ctx.project_manifest_path(uuid);
-        fs::write(file_path, &proj_manifest_data)?;
+        write_atomic(&file_path, &proj_manifest_data)?;
     } else {
         return Err(Errors::InternalError);
     }
@@ -392,7 +396,7 @@ fn create_cell(
         let uuid = project_ref.manifest.clone();
         project_ref.cells.push(name.to_string());
         let manifest_vec = serde_json::to_vec_pretty(&manifest)?;
-        fs::write(ctx.main_manifest_path(), &manifest_vec)?;
+        write_atomic(&ctx.main_manifest_path(), &manifest_vec)?;
 
         let cell_ref = CellRef {
             is_deleted: false,
@@ -418,7 +422,7 @@ fn change_project_description(path: &Path, description: &str) -> Result<(), Erro
     manifest.timestamp = Utc::now();
 
     let json = serde_json::to_vec_pretty(&manifest)?;
-    fs::write(path, json)?;
+    write_atomic(path, &json)?;
     Ok(())
 }
 
@@ -439,7 +443,25 @@ fn change_project_path(
     update_proj_in_main(&ctx, name, project_ref)?;
 
     let json = serde_json::to_vec_pretty(&manifest)?;
-    fs::write(manifest_path, json)?;
+    write_atomic(manifest_path, &json)?;
+    Ok(())
+}
+
+fn check_retention(config: &DenaliToml) -> Result<(), Errors> {
+    if let Some(retention) = &config.root.retention
+        && retention.is_empty()
+    {
+        return Err(Errors::EmptyRetention(config.root.name.clone()));
+    }
+
+    for (name, cell) in &config.cells {
+        if let Some(retention) = &cell.retention
+            && retention.is_empty()
+        {
+            return Err(Errors::EmptyRetention(name.clone()));
+        }
+    }
+
     Ok(())
 }
 
@@ -501,7 +523,7 @@ fn add_cell_to_project(file_path: &Path, name: &str, cell: CellRef) -> Result<()
     }
     manifest.cells.insert(name.to_string(), cell);
     let json = serde_json::to_vec_pretty(&manifest)?;
-    fs::write(file_path, json)?;
+    write_atomic(file_path, &json)?;
     Ok(())
 }
 
@@ -521,7 +543,7 @@ fn make_project_manifest(
     };
 
     let json = serde_json::to_vec_pretty(&project_manifest)?;
-    fs::write(manifest_path, json)?;
+    write_atomic(manifest_path, &json)?;
     Ok(project_manifest)
 }
 
@@ -1,10 +1,20 @@
-use std::{collections::HashSet, fs, io::Read, path::Path};
+use std::{collections::HashSet, fs, path::Path};
 
-use zstd::Decoder;
-
-use crate::utils::{Errors, TreeStruct, context::AppContext, file_type::FileType};
+use crate::utils::{
+    Errors,
+    chunker::parse_chunk_list,
+    context::AppContext,
+    file_type::FileType,
+    journal::recover,
+    pack::{filter_packs, read_packs},
+    tree_codec::parse_tree,
+};
 
 pub fn clean(ctx: &AppContext, is_dry: bool) -> Result<(), Errors> {
+    if !is_dry {
+        recover(ctx)?;
+    }
+
     let mut objects = HashSet::new();
     let mut snapshots: HashSet<String> = HashSet::new();
     mark_entries(ctx, &mut snapshots, &mut objects)?;
@@ -17,6 +27,12 @@ pub fn clean(ctx: &AppContext, is_dry: bool) -> Result<(), Errors> {
     } else {
         delete_snapshots(&ctx.snapshots_path(), &snapshots)?;
         delete_objects(&ctx.objects_path(), &objects)?;
+        filter_packs(&ctx.snapshot_packs_path(), |h| {
+            !snapshots.contains(&hex::encode(h))
+        })?;
+        filter_packs(&ctx.object_packs_path(), |h| {
+            objects.contains(&hex::encode(h))
+        })?;
     }
     Ok(())
 }
@@ -24,7 +40,7 @@ pub fn clean(ctx: &AppContext, is_dry: bool) -> Result<(), Errors> {
 fn delete_objects(path: &Path, objects: &HashSet<String>) -> Result<(), Errors> {
     for dir_entry in fs::read_dir(&path)? {
         let dir_entry = dir_entry?;
-        if !dir_entry.file_type()?.is_dir() {
+        if !dir_entry.file_type()?.is_dir() || dir_entry.file_name() == "pack" {
             continue;
         }
 
@@ -64,7 +80,7 @@ fn mark_templates(
 fn delete_snapshots(path: &Path, snapshots: &HashSet<String>) -> Result<(), Errors> {
     for dir_entry in fs::read_dir(&path)? {
         let dir_entry = dir_entry?;
-        if !dir_entry.file_type()?.is_dir() {
+        if !dir_entry.file_type()?.is_dir() || dir_entry.file_name() == "pack" {
             continue;
         }
 
@@ -98,7 +114,9 @@ fn mark_entries(
         if !project_ref.is_deleted {
             let project_manifest = ctx.load_project_manifest(project_ref.manifest.clone())?;
             for (_, snapshot) in &project_manifest.snapshots {
-                good_entries.insert(snapshot.hash.clone());
+                if !snapshot.is_deleted {
+                    good_entries.insert(snapshot.hash.clone());
+                }
             }
             for (_, cell_ref) in &project_manifest.cells {
                 if !cell_ref.is_deleted {
@@ -126,6 +144,9 @@ fn mark_orphans(
     let path = ctx.snapshots_path();
     for entry in fs::read_dir(path)? {
         let dir = entry?;
+        if dir.file_name() == "pack" {
+            continue;
+        }
         for file in fs::read_dir(dir.path())? {
             let dir_name = dir.file_name().to_string_lossy().to_string();
             let filename = file?.file_name().to_string_lossy().to_string();
@@ -135,6 +156,14 @@ fn mark_orphans(
             }
         }
     }
+    for pack in read_packs(&ctx.snapshot_packs_path())? {
+        for hash in pack.hashes() {
+            let full_hash = hex::encode(hash);
+            if !good_entries.contains(&full_hash) {
+                snapshots.insert(full_hash);
+            }
+        }
+    }
     for snapshot in good_entries.iter() {
         let snap = ctx.load_snapshot(snapshot.to_string())?;
         mark_objects(ctx, &snap.root, snapshots, objects)?;
@@ -148,68 +177,87 @@ fn mark_objects(
     snapshots: &mut HashSet<String>,
     good_entries: &mut HashSet<String>,
 ) -> Result<(), Errors> {
-    let dir = &hash[..3];
-    let file = &hash[3..];
-    let path = ctx.objects_path().join(dir).join(file);
-    if !path.exists() {
+    if !good_entries.insert(hash.to_string()) {
         return Ok(());
     }
-    let mut file = fs::File::open(path)?;
-    let mut tree_cmp = Vec::new();
-    file.read_to_end(&mut tree_cmp)?;
-
-    let mut tree = Vec::new();
-    {
-        let mut decoder = Decoder::new(&tree_cmp[..])?;
-        decoder.read_to_end(&mut tree)?;
+    if !ctx.has_object(hash)? {
+        return Ok(());
     }
+    let tree = ctx.load_object(hash.to_string())?;
 
     let entries = parse_tree(&tree)?;
 
-    good_entries.insert(hash.to_string());
-
     for entry in entries {
         let mode_u32 = u32::from_be_bytes(entry.mode);
         let mode = FileType::from_mode(mode_u32);
         if mode == FileType::Cell {
-            good_entries.insert(hex::encode(entry.hash));
-        } else if mode == FileType::Directory {
             snapshots.remove(&hex::encode(entry.hash));
             let snap = ctx.load_snapshot(hex::encode(entry.hash))?;
             mark_objects(ctx, &snap.root, snapshots, good_entries)?;
-        } else {
+        } else if mode == FileType::Directory {
             mark_objects(ctx, &hex::encode(entry.hash), snapshots, good_entries)?;
+        } else if mode == FileType::Chunked {
+            let list = ctx.load_object(hex::encode(entry.hash))?;
+            for (chunk, _) in parse_chunk_list(&list)? {
+                good_entries.insert(hex::encode(chunk));
+            }
+            good_entries.insert(hex::encode(entry.hash));
+        } else {
+            mark_blob(ctx, hex::encode(entry.hash), good_entries)?;
         }
     }
     Ok(())
 }
 
-fn parse_tree(tree: &Vec<u8>) -> Result<Vec<TreeStruct>, Errors> {
-    let mut entries = Vec::new();
-
-    let mut i = 0;
-    while i < tree.len() {
-        let mode_start = i;
-        while tree[i] != b' ' {
-            i += 1;
+/// Marks a blob and, when it is stored as a delta, every base it needs.
+fn mark_blob(
+    ctx: &AppContext,
+    hash: String,
+    good_entries: &mut HashSet<String>,
+) -> Result<(), Errors> {
+    let mut next = Some(hash);
+    while let Some(hash) = next.take() {
+        if !good_entries.insert(hash.clone()) || !ctx.has_object(&hash)? {
+            break;
         }
-        let mode: [u8; 4] = tree[mode_start..i].try_into()?;
-        i += 1;
+        next = ctx
+            .delta_header(&hash)?
+            .map(|header| hex::encode(header.base));
+    }
+    Ok(())
+}
 
-        while tree[i] != 0 {
-            i += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::compression::Compression;
+
+    #[test]
+    fn mark_blob_keeps_delta_bases() {
+        let (_dir, ctx) = AppContext::scratch();
+
+        let version = |n: usize| {
+            let lines: String = (0..4000)
+                .map(|i| format!("line {}\n", i * 7919 % 10007))
+                .collect();
+            format!("{}version {}\n", lines, n)
+        };
+        let mut chain = vec![ctx.save_object(version(0).into_bytes()).unwrap()];
+        for n in 1..4 {
+            let base = *chain.last().unwrap();
+            let hash = ctx
+                .save_object_delta(version(n).into_bytes(), &base, Compression::default())
+                .unwrap();
+            assert!(ctx.delta_header(&hex::encode(hash)).unwrap().is_some());
+            chain.push(hash);
         }
-        i += 1;
+        let unrelated = ctx.save_object(b"unrelated".to_vec()).unwrap();
 
-        let hash: [u8; 32] = tree[i..i + 32].try_into()?;
-        i += 32;
+        let mut good_entries = HashSet::new();
+        mark_blob(&ctx, hex::encode(chain[3]), &mut good_entries).unwrap();
 
-        entries.push(TreeStruct {
-            name: String::new(),
-            mode: mode,
-            hash: hash,
-        });
+        let expected: HashSet<String> = chain.iter().map(hex::encode).collect();
+        assert_eq!(good_entries, expected);
+        assert!(!good_entries.contains(&hex::encode(unrelated)));
     }
-
-    Ok(entries)
 }
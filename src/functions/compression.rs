@@ -0,0 +1,48 @@
+use crate::utils::{
+    Errors, MainManifest, compression::Compression, context::AppContext, store_config::StoreConfig,
+};
+
+/// Shows the compression of the store and the dictionaries of its projects, or
+/// changes them. `value` becomes the default of every cell without a setting of
+/// its own, and `no_dictionary` stops compressing new objects of that project
+/// with its trained dictionary. Objects already stored keep their compression.
+pub fn compression(
+    ctx: &AppContext,
+    value: Option<Compression>,
+    no_dictionary: Option<String>,
+) -> Result<(), Errors> {
+    let manifest: MainManifest = ctx.load_main_manifest()?;
+    let mut config = StoreConfig::load(ctx)?;
+
+    if value.is_none() && no_dictionary.is_none() {
+        println!("Compression: {}", config.compression);
+        for (name, proj) in &manifest.projects {
+            if let Some(id) = config.dictionaries.get(&proj.manifest) {
+                println!(" {} uses dictionary {}", name, id);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(compression) = value {
+        config.compression = compression;
+        println!("New objects are compressed with {}", compression);
+    }
+
+    if let Some(project) = no_dictionary {
+        let proj = manifest
+            .projects
+            .get(&project)
+            .ok_or_else(|| Errors::ProjectNotFound(project.clone()))?;
+        if config.dictionaries.remove(&proj.manifest).is_some() {
+            println!(
+                "New objects of {} are compressed without a dictionary",
+                project
+            );
+        } else {
+            println!("{} has no dictionary", project);
+        }
+    }
+
+    config.write(ctx)
+}
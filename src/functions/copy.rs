@@ -5,8 +5,9 @@ use std::{
 };
 
 use crate::utils::{
-    CellRef, Errors, MainManifest, ProjectManifest, ProjectRef, context::AppContext,
-    file_type::FileType, parse_name,
+    CellRef, Errors, MainManifest, ProjectManifest, ProjectRef, Snapshot,
+    chunker::parse_chunk_list, context::AppContext, file_type::FileType, lock::LockMode,
+    parse_name, store_config::StoreConfig, tree_codec::parse_tree,
 };
 
 pub fn copy(ctx: &AppContext, project: String, path: Option<&Path>) -> Result<(), Errors> {
@@ -19,7 +20,22 @@ pub fn copy(ctx: &AppContext, project: String, path: Option<&Path>) -> Result<()
         None => env::current_dir()?,
     };
 
-    let dest = AppContext::new(Some(dir.clone()))?;
+    let mut dest = AppContext::new(Some(dir.clone()))?;
+    dest.lock_wait = ctx.lock_wait;
+    let _dest_lock = if dest.root != ctx.root {
+        let lock = dest.lock(LockMode::Exclusive)?;
+        StoreConfig::check(&dest, LockMode::Exclusive)?;
+        Some(lock)
+    } else {
+        None
+    };
+    // Trees are copied as they are, so both stores have to name objects alike.
+    if ctx.object_format()? != dest.object_format()? {
+        return Err(Errors::ObjectFormatMismatch(
+            ctx.object_format()?,
+            dest.object_format()?,
+        ));
+    }
 
     if !dir.exists() {
         return Err(Errors::DoesntExist(dir));
@@ -104,9 +120,7 @@ fn copy_cell(
         if snapshot.is_deleted {
             continue;
         }
-        let snapshot = ctx.load_snapshot(snapshot.hash.clone())?;
-        let bytes = serde_json::to_vec(&snapshot)?;
-        dest.save_snapshot(bytes)?;
+        let snapshot = copy_snapshot(ctx, snapshot.hash.clone(), dest)?;
         copy_tree(ctx, snapshot.root, dest, &mut copied)?;
     }
 
@@ -141,9 +155,7 @@ fn copy_project(
         if snapshot.is_deleted {
             continue;
         }
-        let snapshot = ctx.load_snapshot(snapshot.hash.clone())?;
-        let bytes = serde_json::to_vec(&snapshot)?;
-        dest.save_snapshot(bytes)?;
+        let snapshot = copy_snapshot(ctx, snapshot.hash.clone(), dest)?;
         copy_tree(ctx, snapshot.root, dest, copied)?;
     }
 
@@ -159,9 +171,7 @@ fn copy_project(
             if snapshot.is_deleted {
                 continue;
             }
-            let snapshot = ctx.load_snapshot(snapshot.hash.clone())?;
-            let bytes = serde_json::to_vec(&snapshot)?;
-            dest.save_snapshot(bytes)?;
+            let snapshot = copy_snapshot(ctx, snapshot.hash.clone(), dest)?;
             copy_tree(ctx, snapshot.root, dest, copied)?;
         }
     }
@@ -191,9 +201,7 @@ fn copy_all(
             if copied.contains(&snapshot.hash) {
                 continue;
             }
-            let snapshot = ctx.load_snapshot(snapshot.hash.clone())?;
-            let bytes = serde_json::to_vec(&snapshot)?;
-            dest.save_snapshot(bytes)?;
+            let snapshot = copy_snapshot(ctx, snapshot.hash.clone(), dest)?;
             copy_tree(ctx, snapshot.root, dest, copied)?;
         }
 
@@ -212,9 +220,7 @@ fn copy_all(
                 if copied.contains(&snapshot.hash) {
                     continue;
                 }
-                let snapshot = ctx.load_snapshot(snapshot.hash.clone())?;
-                let bytes = serde_json::to_vec(&snapshot)?;
-                dest.save_snapshot(bytes)?;
+                let snapshot = copy_snapshot(ctx, snapshot.hash.clone(), dest)?;
                 copy_tree(ctx, snapshot.root, dest, copied)?;
             }
         }
@@ -225,6 +231,12 @@ fn copy_all(
     Ok(())
 }
 
+/// Stores the snapshot in `dest` byte for byte, so it keeps its hash.
+fn copy_snapshot(ctx: &AppContext, hash: String, dest: &AppContext) -> Result<Snapshot, Errors> {
+    dest.save_snapshot(ctx.load_snapshot_bytes(&hash)?)?;
+    ctx.load_snapshot(hash)
+}
+
 fn copy_tree(
     ctx: &AppContext,
     hash: String,
@@ -240,16 +252,20 @@ fn copy_tree(
             continue;
         }
         copied.insert(hex::encode(entry.hash));
-        if FileType::from_mode(u32::from_be_bytes(entry.mode)) == FileType::Cell {
-            let snapshot = ctx.load_snapshot(hex::encode(entry.hash))?;
-            let bytes = serde_json::to_vec(&snapshot)?;
-            dest.save_snapshot(bytes)?;
+        let filetype = FileType::from_mode(u32::from_be_bytes(entry.mode));
+        if filetype == FileType::Cell {
+            let snapshot = copy_snapshot(ctx, hex::encode(entry.hash), dest)?;
             copy_tree(ctx, snapshot.root, dest, copied)?;
-        }
-        if FileType::from_mode(u32::from_be_bytes(entry.mode)) == FileType::Directory {
+        } else if filetype == FileType::Directory {
             copy_tree(ctx, hex::encode(entry.hash), dest, copied)?;
         } else {
             let data = ctx.load_object(hex::encode(entry.hash))?;
+            if filetype == FileType::Chunked {
+                for (chunk, _) in parse_chunk_list(&data)? {
+                    let chunk_data = ctx.load_object(hex::encode(chunk))?;
+                    dest.save_object(chunk_data)?;
+                }
+            }
             dest.save_object(data)?;
         }
     }
@@ -258,37 +274,3 @@ fn copy_tree(
 
     Ok(())
 }
-
-struct TreeStruct {
-    mode: [u8; 4],
-    hash: [u8; 32],
-}
-
-fn parse_tree(tree: &Vec<u8>) -> Result<Vec<TreeStruct>, Errors> {
-    let mut entries = Vec::new();
-
-    let mut i = 0;
-    while i < tree.len() {
-        let mode_start = i;
-        while tree[i] != b' ' {
-            i += 1;
-        }
-        let mode: [u8; 4] = tree[mode_start..i].try_into()?;
-        i += 1;
-
-        while tree[i] != 0 {
-            i += 1;
-        }
-        i += 1;
-
-        let hash: [u8; 32] = tree[i..i + 32].try_into()?;
-        i += 32;
-
-        entries.push(TreeStruct {
-            mode: mode,
-            hash: hash,
-        });
-    }
-
-    Ok(entries)
-}
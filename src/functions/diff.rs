@@ -0,0 +1,486 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    os::unix::{ffi::OsStringExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use std::{
+    fs::{self, File},
+    io::Read,
+};
+
+use crate::utils::{
+    Errors, MainManifest, ProjectManifest, TreeStruct,
+    chunker::{CHUNK_THRESHOLD, content_head, load_content},
+    context::AppContext,
+    escape::escape_path,
+    file_type::FileType,
+    parse_name,
+    text_diff::{BINARY_PROBE, is_binary, unified_diff},
+    tree_codec::parse_tree,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+    ModeChanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub old_mode: u32,
+    pub new_mode: u32,
+    pub old_hash: Option<[u8; 32]>,
+    pub new_hash: Option<[u8; 32]>,
+    pub live: Option<PathBuf>,
+}
+
+pub fn diff(
+    ctx: &AppContext,
+    project: String,
+    from: String,
+    to: Option<String>,
+    patch: bool,
+) -> Result<(), Errors> {
+    let (project_name, cell) = parse_name(project)?;
+
+    let manifest: MainManifest = ctx.load_main_manifest()?;
+    let proj_ref = manifest
+        .projects
+        .get(&project_name)
+        .ok_or_else(|| Errors::NotInitialised(PathBuf::from(&project_name)))?;
+
+    if let Some(cell_name) = &cell
+        && !proj_ref.cells.contains(cell_name)
+    {
+        return Err(Errors::NotInitialised(PathBuf::from(cell_name)));
+    }
+
+    let project_manifest = ctx.load_project_manifest(proj_ref.manifest.clone())?;
+
+    let old_snap = snapshot_hash(&project_manifest, cell.as_deref(), &from)?;
+    let new_snap = match to {
+        Some(name) => snapshot_hash(&project_manifest, cell.as_deref(), &name)?,
+        None => match &cell {
+            Some(cell_name) => project_manifest
+                .cells
+                .get(cell_name)
+                .ok_or(Errors::InternalError)?
+                .latest
+                .clone(),
+            None => proj_ref.latest.clone(),
+        },
+    };
+
+    if new_snap.is_empty() {
+        return Err(Errors::NoMatches);
+    }
+
+    let old_root = ctx.load_snapshot(old_snap)?.root;
+    let new_root = ctx.load_snapshot(new_snap)?.root;
+
+    let mut changes = Vec::new();
+    diff_trees(
+        ctx,
+        &HashMap::new(),
+        Some(&old_root),
+        Some(&new_root),
+        Path::new(""),
+        &mut changes,
+    )?;
+
+    if patch {
+        print_patches(ctx, &changes)?;
+    } else {
+        print_changes(&changes);
+    }
+
+    Ok(())
+}
+
+fn snapshot_hash(
+    manifest: &ProjectManifest,
+    cell: Option<&str>,
+    name: &str,
+) -> Result<String, Errors> {
+    let snapshots = match cell {
+        Some(cell_name) => {
+            let cell_ref = manifest.cells.get(cell_name).ok_or(Errors::InternalError)?;
+            &cell_ref.snapshots
+        }
+        None => &manifest.snapshots,
+    };
+
+    match snapshots.get(name) {
+        Some(snapshot) if !snapshot.is_deleted => Ok(snapshot.hash.clone()),
+        _ => Err(Errors::SnapshotDoesNotExist(name.to_string())),
+    }
+}
+
+fn load_tree(
+    ctx: &AppContext,
+    trees: &HashMap<String, Vec<u8>>,
+    hash: &str,
+) -> Result<Vec<TreeStruct>, Errors> {
+    match trees.get(hash) {
+        Some(tree) => parse_tree(tree),
+        None => parse_tree(&ctx.load_object(hash.to_string())?),
+    }
+}
+
+pub fn diff_trees(
+    ctx: &AppContext,
+    trees: &HashMap<String, Vec<u8>>,
+    old: Option<&str>,
+    new: Option<&str>,
+    prefix: &Path,
+    changes: &mut Vec<Change>,
+) -> Result<(), Errors> {
+    if old.is_some() && old == new {
+        return Ok(());
+    }
+
+    let mut entries: BTreeMap<OsString, (Option<TreeStruct>, Option<TreeStruct>)> = BTreeMap::new();
+
+    if let Some(hash) = old {
+        for entry in load_tree(ctx, trees, hash)? {
+            let name = entry.name.clone();
+            entries.entry(name).or_default().0 = Some(entry);
+        }
+    }
+
+    if let Some(hash) = new {
+        for entry in load_tree(ctx, trees, hash)? {
+            let name = entry.name.clone();
+            entries.entry(name).or_default().1 = Some(entry);
+        }
+    }
+
+    for (name, pair) in entries {
+        let path = prefix.join(&name);
+        match pair {
+            (Some(old_entry), Some(new_entry)) => {
+                diff_entries(ctx, trees, &old_entry, &new_entry, &path, changes)?;
+            }
+            (Some(old_entry), None) => {
+                report_entry(ctx, trees, &old_entry, &path, ChangeKind::Removed, changes)?;
+            }
+            (None, Some(new_entry)) => {
+                report_entry(ctx, trees, &new_entry, &path, ChangeKind::Added, changes)?;
+            }
+            (None, None) => continue,
+        }
+    }
+
+    Ok(())
+}
+
+fn diff_entries(
+    ctx: &AppContext,
+    trees: &HashMap<String, Vec<u8>>,
+    old: &TreeStruct,
+    new: &TreeStruct,
+    path: &Path,
+    changes: &mut Vec<Change>,
+) -> Result<(), Errors> {
+    if old.hash == new.hash && old.mode == new.mode {
+        return Ok(());
+    }
+
+    let old_mode = u32::from_be_bytes(old.mode);
+    let new_mode = u32::from_be_bytes(new.mode);
+    let old_type = FileType::from_mode(old_mode);
+    let new_type = FileType::from_mode(new_mode);
+
+    if old_type != new_type && !(old_type.is_file() && new_type.is_file()) {
+        report_entry(ctx, trees, old, path, ChangeKind::Removed, changes)?;
+        report_entry(ctx, trees, new, path, ChangeKind::Added, changes)?;
+        return Ok(());
+    }
+
+    if old_mode & 0o7777 != new_mode & 0o7777 {
+        changes.push(Change {
+            path: path.to_path_buf(),
+            kind: ChangeKind::ModeChanged,
+            old_mode,
+            new_mode,
+            old_hash: Some(old.hash),
+            new_hash: Some(new.hash),
+            live: None,
+        });
+    }
+
+    if old.hash == new.hash {
+        return Ok(());
+    }
+
+    match old_type {
+        FileType::Directory => {
+            diff_trees(
+                ctx,
+                trees,
+                Some(&hex::encode(old.hash)),
+                Some(&hex::encode(new.hash)),
+                path,
+                changes,
+            )?;
+        }
+        FileType::Cell => {
+            let old_root = ctx.load_snapshot(hex::encode(old.hash))?.root;
+            let new_root = ctx.load_snapshot(hex::encode(new.hash))?.root;
+            diff_trees(ctx, trees, Some(&old_root), Some(&new_root), path, changes)?;
+        }
+        _ => changes.push(Change {
+            path: path.to_path_buf(),
+            kind: ChangeKind::Modified,
+            old_mode,
+            new_mode,
+            old_hash: Some(old.hash),
+            new_hash: Some(new.hash),
+            live: None,
+        }),
+    }
+
+    Ok(())
+}
+
+fn report_entry(
+    ctx: &AppContext,
+    trees: &HashMap<String, Vec<u8>>,
+    entry: &TreeStruct,
+    path: &Path,
+    kind: ChangeKind,
+    changes: &mut Vec<Change>,
+) -> Result<(), Errors> {
+    let mode = u32::from_be_bytes(entry.mode);
+
+    if FileType::from_mode(mode) == FileType::Directory {
+        let hash = hex::encode(entry.hash);
+        return match kind {
+            ChangeKind::Added => diff_trees(ctx, trees, None, Some(&hash), path, changes),
+            _ => diff_trees(ctx, trees, Some(&hash), None, path, changes),
+        };
+    }
+
+    let (old_mode, new_mode, old_hash, new_hash) = match kind {
+        ChangeKind::Added => (0, mode, None, Some(entry.hash)),
+        _ => (mode, 0, Some(entry.hash), None),
+    };
+
+    changes.push(Change {
+        path: path.to_path_buf(),
+        kind,
+        old_mode,
+        new_mode,
+        old_hash,
+        new_hash,
+        live: None,
+    });
+
+    Ok(())
+}
+
+pub fn print_changes(changes: &[Change]) {
+    if changes.is_empty() {
+        println!("No changes");
+        return;
+    }
+
+    for change in changes {
+        let path = escape_path(&change.path);
+        match change.kind {
+            ChangeKind::Added => println!(" {} {}", "added:   ".green(), path.green()),
+            ChangeKind::Removed => println!(" {} {}", "removed: ".red(), path.red()),
+            ChangeKind::Modified => println!(" {} {}", "modified:".yellow(), path.yellow()),
+            ChangeKind::ModeChanged => println!(
+                " {} {} {}",
+                "mode:    ".cyan(),
+                path.cyan(),
+                format!(
+                    "({:o} -> {:o})",
+                    change.old_mode & 0o7777,
+                    change.new_mode & 0o7777
+                )
+                .dimmed()
+            ),
+        }
+    }
+}
+
+/// Content of one side of a change. Files too large to diff by line are not
+/// loaded, only their head is kept to tell binary from text. Symlinks are
+/// diffed by their target, special files have no content to diff.
+enum Content {
+    Whole(Vec<u8>),
+    Head(Vec<u8>),
+    Special(FileType),
+}
+
+impl Content {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Content::Whole(data) | Content::Head(data) => data,
+            Content::Special(_) => &[],
+        }
+    }
+
+    fn special(&self) -> Option<&'static str> {
+        match self {
+            Content::Special(FileType::Fifo) => Some("fifo"),
+            Content::Special(FileType::CharDevice) => Some("character device"),
+            Content::Special(FileType::BlockDevice) => Some("block device"),
+            Content::Special(FileType::Socket) => Some("socket"),
+            Content::Special(FileType::Directory) => Some("directory"),
+            Content::Special(_) => Some("special file"),
+            _ => None,
+        }
+    }
+}
+
+fn is_special(file_type: FileType) -> bool {
+    matches!(
+        file_type,
+        FileType::Fifo | FileType::CharDevice | FileType::BlockDevice | FileType::Socket
+    )
+}
+
+fn change_content(ctx: &AppContext, hash: Option<[u8; 32]>, mode: u32) -> Result<Content, Errors> {
+    let Some(hash) = hash else {
+        return Ok(Content::Whole(Vec::new()));
+    };
+
+    let hash = hex::encode(hash);
+    match FileType::from_mode(mode) {
+        FileType::Chunked => Ok(Content::Head(content_head(ctx, &hash, mode, BINARY_PROBE)?)),
+        file_type if is_special(file_type) => Ok(Content::Special(file_type)),
+        _ => Ok(Content::Whole(load_content(ctx, &hash, mode)?)),
+    }
+}
+
+/// Content of a path in the working tree. Only regular files are opened, so a
+/// fifo never blocks the diff.
+fn live_content(path: &Path) -> Result<Content, Errors> {
+    let meta = fs::symlink_metadata(path)?;
+    let file_type = FileType::from_mode(meta.mode());
+    if file_type == FileType::Symlink {
+        let target = fs::read_link(path)?;
+        return Ok(Content::Whole(target.into_os_string().into_vec()));
+    }
+    if file_type != FileType::Regular {
+        return Ok(Content::Special(file_type));
+    }
+
+    let file = File::open(path)?;
+    if meta.len() < CHUNK_THRESHOLD {
+        let mut content = Vec::new();
+        (&file).read_to_end(&mut content)?;
+        return Ok(Content::Whole(content));
+    }
+
+    let mut head = Vec::new();
+    file.take(BINARY_PROBE as u64).read_to_end(&mut head)?;
+    Ok(Content::Head(head))
+}
+
+pub fn print_patches(ctx: &AppContext, changes: &[Change]) -> Result<(), Errors> {
+    if changes.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+
+    for change in changes {
+        let path = escape_path(&change.path);
+
+        if change.kind == ChangeKind::ModeChanged {
+            println!(
+                "{}",
+                format!(
+                    "mode {} {:o} -> {:o}",
+                    path,
+                    change.old_mode & 0o7777,
+                    change.new_mode & 0o7777
+                )
+                .bold()
+            );
+            continue;
+        }
+
+        let is_cell = |mode: u32| FileType::from_mode(mode) == FileType::Cell;
+        if is_cell(change.old_mode) || is_cell(change.new_mode) {
+            print_changes(std::slice::from_ref(change));
+            continue;
+        }
+
+        let old = change_content(ctx, change.old_hash, change.old_mode)?;
+        let new = match (&change.live, change.kind) {
+            (Some(live), ChangeKind::Added | ChangeKind::Modified) => live_content(live)?,
+            _ => change_content(ctx, change.new_hash, change.new_mode)?,
+        };
+
+        let old_label = match change.kind {
+            ChangeKind::Added => "/dev/null".to_string(),
+            _ => format!("a/{}", path),
+        };
+        let new_label = match change.kind {
+            ChangeKind::Removed => "/dev/null".to_string(),
+            _ => format!("b/{}", path),
+        };
+
+        println!("{}", format!("diff {} {}", old_label, new_label).bold());
+
+        if old.special().is_some() || new.special().is_some() {
+            let describe = |label: &str, content: &Content| match content.special() {
+                Some(kind) => format!("{} ({})", label, kind),
+                None => label.to_string(),
+            };
+            println!(
+                "Files {} and {} differ",
+                describe(&old_label, &old),
+                describe(&new_label, &new)
+            );
+            continue;
+        }
+        if is_binary(old.bytes()) || is_binary(new.bytes()) {
+            println!("Binary files {} and {} differ", old_label, new_label);
+            continue;
+        }
+        let (Content::Whole(old), Content::Whole(new)) = (old, new) else {
+            println!("Files {} and {} differ", old_label, new_label);
+            continue;
+        };
+
+        let text = unified_diff(
+            &String::from_utf8_lossy(&old),
+            &String::from_utf8_lossy(&new),
+            &old_label,
+            &new_label,
+        );
+
+        match text {
+            Some(text) => {
+                for line in text.lines() {
+                    if line.starts_with("+++") || line.starts_with("---") {
+                        println!("{}", line.bold());
+                    } else if line.starts_with("@@") {
+                        println!("{}", line.cyan());
+                    } else if line.starts_with('+') {
+                        println!("{}", line.green());
+                    } else if line.starts_with('-') {
+                        println!("{}", line.red());
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+            }
+            None => println!("Files {} and {} differ", old_label, new_label),
+        }
+    }
+
+    Ok(())
+}
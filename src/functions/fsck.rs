@@ -0,0 +1,390 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use colored::*;
+
+use crate::utils::{
+    Errors, chunker::parse_chunk_list, context::AppContext, escape::escape_path,
+    file_type::FileType, pack::PackIndex, tree_codec::parse_tree,
+};
+
+#[derive(Default)]
+struct Report {
+    corrupt: Vec<String>,
+    missing: Vec<String>,
+    dangling: Vec<String>,
+}
+
+struct Walker<'a> {
+    ctx: &'a AppContext,
+    stored_objects: HashSet<String>,
+    stored_snapshots: HashSet<String>,
+    seen_objects: HashSet<String>,
+    seen_snapshots: HashSet<String>,
+    report: Report,
+}
+
+pub fn fsck(ctx: &AppContext) -> Result<(), Errors> {
+    let mut report = Report::default();
+
+    let stored_objects = verify_store(
+        ctx,
+        &ctx.objects_path(),
+        &ctx.object_packs_path(),
+        "object",
+        &mut report,
+    )?;
+    let stored_snapshots = verify_store(
+        ctx,
+        &ctx.snapshots_path(),
+        &ctx.snapshot_packs_path(),
+        "snapshot",
+        &mut report,
+    )?;
+
+    let mut walker = Walker {
+        ctx,
+        stored_objects,
+        stored_snapshots,
+        seen_objects: HashSet::new(),
+        seen_snapshots: HashSet::new(),
+        report,
+    };
+
+    let manifest = ctx.load_main_manifest()?;
+    let mut projects: Vec<_> = manifest.projects.iter().collect();
+    projects.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (name, project_ref) in projects {
+        if project_ref.is_deleted {
+            continue;
+        }
+        let project_manifest = match ctx.load_project_manifest(project_ref.manifest.clone()) {
+            Ok(m) => m,
+            Err(_) => {
+                walker
+                    .report
+                    .missing
+                    .push(format!("manifest of project \"{}\"", name));
+                continue;
+            }
+        };
+
+        let mut snapshots: Vec<_> = project_manifest.snapshots.iter().collect();
+        snapshots.sort_by(|a, b| a.0.cmp(b.0));
+        for (snap_name, snapshot) in snapshots {
+            if !snapshot.is_deleted {
+                let label = format!("{} {}", name, snap_name);
+                walker.check_snapshot(&snapshot.hash, &label)?;
+            }
+        }
+
+        let mut cells: Vec<_> = project_manifest.cells.iter().collect();
+        cells.sort_by(|a, b| a.0.cmp(b.0));
+        for (cell_name, cell_ref) in cells {
+            if cell_ref.is_deleted {
+                continue;
+            }
+            let mut snapshots: Vec<_> = cell_ref.snapshots.iter().collect();
+            snapshots.sort_by(|a, b| a.0.cmp(b.0));
+            for (snap_name, snapshot) in snapshots {
+                if !snapshot.is_deleted {
+                    let label = format!("{}@{} {}", cell_name, name, snap_name);
+                    walker.check_snapshot(&snapshot.hash, &label)?;
+                }
+            }
+        }
+    }
+
+    for (name, template_ref) in &manifest.templates {
+        let label = format!("template {}", name);
+        walker.check_tree(&template_ref.tree, &label, Path::new(""))?;
+    }
+
+    let mut report = walker.report;
+    for (stored, seen, kind) in [
+        (&walker.stored_objects, &walker.seen_objects, "object"),
+        (&walker.stored_snapshots, &walker.seen_snapshots, "snapshot"),
+    ] {
+        let mut dangling: Vec<&String> = stored.difference(seen).collect();
+        dangling.sort();
+        report
+            .dangling
+            .extend(dangling.into_iter().map(|h| format!("{} {}", kind, h)));
+    }
+
+    print_report(
+        &report,
+        walker.stored_objects.len(),
+        walker.stored_snapshots.len(),
+    );
+
+    let problems = report.corrupt.len() + report.missing.len();
+    if problems > 0 {
+        return Err(Errors::StoreDamaged(problems));
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &Report, objects: usize, snapshots: usize) {
+    for entry in &report.corrupt {
+        println!("{} {}", "corrupt: ".red().bold(), entry);
+    }
+    for entry in &report.missing {
+        println!("{} {}", "missing: ".red(), entry);
+    }
+    for entry in &report.dangling {
+        println!("{} {}", "dangling:".yellow(), entry);
+    }
+
+    println!(
+        "Checked {} objects and {} snapshots: {} corrupt, {} missing, {} dangling",
+        objects,
+        snapshots,
+        report.corrupt.len(),
+        report.missing.len(),
+        report.dangling.len()
+    );
+    if !report.dangling.is_empty() {
+        println!(
+            "Dangling entries are not referenced anywhere and are removed by \"denali clean\""
+        );
+    }
+}
+
+/// Rehashes every loose file and packed entry under `base`. Returns the hashes
+/// of everything that is stored, corrupt or not.
+fn verify_store(
+    ctx: &AppContext,
+    base: &Path,
+    pack_dir: &Path,
+    kind: &str,
+    report: &mut Report,
+) -> Result<HashSet<String>, Errors> {
+    let mut stored = HashSet::new();
+    let legacy = ctx.object_format()? == 1;
+
+    for dir_entry in fs::read_dir(base)? {
+        let dir_entry = dir_entry?;
+        if !dir_entry.file_type()?.is_dir() || dir_entry.file_name() == "pack" {
+            continue;
+        }
+
+        for file_entry in fs::read_dir(dir_entry.path())? {
+            let file_entry = file_entry?;
+            let dir_name = dir_entry.file_name().to_string_lossy().to_string();
+            let file_name = file_entry.file_name().to_string_lossy().to_string();
+            let full_hash = format!("{}{}", dir_name, file_name);
+
+            let path = file_entry.path();
+            let mut hash = [0u8; 32];
+            let intact = hex::decode_to_slice(&full_hash, &mut hash).is_ok()
+                && is_intact(ctx, || Ok(File::open(&path)?), &hash, legacy);
+            if !intact {
+                report.corrupt.push(format!("{} {}", kind, full_hash));
+            }
+            stored.insert(full_hash);
+        }
+    }
+
+    if !pack_dir.is_dir() {
+        return Ok(stored);
+    }
+
+    for entry in fs::read_dir(pack_dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != "idx") {
+            continue;
+        }
+
+        let pack = match PackIndex::read(&path) {
+            Ok(pack) => pack,
+            Err(_) => {
+                report
+                    .corrupt
+                    .push(format!("pack index {}", path.display()));
+                continue;
+            }
+        };
+
+        for hash in pack.hashes() {
+            let full_hash = hex::encode(hash);
+            let intact = is_intact(
+                ctx,
+                || {
+                    pack.open_entry(&hash)?
+                        .ok_or(Errors::ObjectNotFound(full_hash.clone()))
+                },
+                &hash,
+                legacy,
+            );
+            if !intact {
+                report.corrupt.push(format!(
+                    "{} {} in {}",
+                    kind,
+                    full_hash,
+                    pack.pack_path().display()
+                ));
+            }
+            stored.insert(full_hash);
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Entries are named after the hash of their content. Only `legacy` stores, not
+/// migrated yet, may still name them after their compressed bytes.
+fn is_intact<R: Read>(
+    ctx: &AppContext,
+    open: impl Fn() -> Result<R, Errors>,
+    hash: &[u8; 32],
+    legacy: bool,
+) -> bool {
+    let hashes_to = |content: bool| -> Result<bool, Errors> {
+        let mut hasher = blake3::Hasher::new();
+        if content {
+            io::copy(&mut ctx.decompress(open()?)?, &mut hasher)?;
+        } else {
+            io::copy(&mut open()?, &mut hasher)?;
+        }
+        Ok(hasher.finalize().as_bytes() == hash)
+    };
+
+    hashes_to(true).unwrap_or(false) || (legacy && hashes_to(false).unwrap_or(false))
+}
+
+impl Walker<'_> {
+    fn check_snapshot(&mut self, hash: &str, label: &str) -> Result<(), Errors> {
+        if !self.seen_snapshots.insert(hash.to_string()) {
+            return Ok(());
+        }
+        if !self.stored_snapshots.contains(hash) {
+            self.report
+                .missing
+                .push(format!("snapshot {} ({})", hash, label));
+            return Ok(());
+        }
+
+        match self.ctx.load_snapshot(hash.to_string()) {
+            Ok(snapshot) => self.check_tree(&snapshot.root, label, Path::new("")),
+            Err(_) => {
+                self.report
+                    .corrupt
+                    .push(format!("snapshot {} does not parse ({})", hash, label));
+                Ok(())
+            }
+        }
+    }
+
+    fn check_tree(&mut self, hash: &str, label: &str, path: &Path) -> Result<(), Errors> {
+        if !self.seen_objects.insert(hash.to_string()) {
+            return Ok(());
+        }
+        if !self.stored_objects.contains(hash) {
+            self.report
+                .missing
+                .push(format!("tree {} ({}: {})", hash, label, escape_path(path)));
+            return Ok(());
+        }
+
+        let entries = match self
+            .ctx
+            .load_object(hash.to_string())
+            .ok()
+            .and_then(|t| parse_tree(&t).ok())
+        {
+            Some(entries) => entries,
+            None => {
+                self.report.corrupt.push(format!(
+                    "tree {} does not parse ({}: {})",
+                    hash,
+                    label,
+                    escape_path(path)
+                ));
+                return Ok(());
+            }
+        };
+
+        for entry in entries {
+            let entry_path = path.join(&entry.name);
+            let entry_hash = hex::encode(entry.hash);
+            match FileType::from_mode(u32::from_be_bytes(entry.mode)) {
+                FileType::Directory => self.check_tree(&entry_hash, label, &entry_path)?,
+                FileType::Cell => {
+                    let cell_label = format!("{}: {}", label, escape_path(&entry_path));
+                    self.check_snapshot(&entry_hash, &cell_label)?;
+                }
+                FileType::Chunked => self.check_chunks(&entry_hash, label, &entry_path)?,
+                _ => self.check_blob(&entry_hash, label, &entry_path),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Follows deltas to their bases, which are missing when the chain breaks.
+    fn check_blob(&mut self, hash: &str, label: &str, path: &Path) {
+        let mut next = Some(hash.to_string());
+        while let Some(hash) = next.take() {
+            if !self.seen_objects.insert(hash.clone()) {
+                break;
+            }
+            if !self.stored_objects.contains(&hash) {
+                self.report.missing.push(format!(
+                    "object {} ({}: {})",
+                    hash,
+                    label,
+                    escape_path(path)
+                ));
+                break;
+            }
+            next = self
+                .ctx
+                .delta_header(&hash)
+                .ok()
+                .flatten()
+                .map(|header| hex::encode(header.base));
+        }
+    }
+
+    fn check_chunks(&mut self, hash: &str, label: &str, path: &Path) -> Result<(), Errors> {
+        if !self.seen_objects.insert(hash.to_string()) {
+            return Ok(());
+        }
+        if !self.stored_objects.contains(hash) {
+            self.report.missing.push(format!(
+                "chunk list {} ({}: {})",
+                hash,
+                label,
+                escape_path(path)
+            ));
+            return Ok(());
+        }
+
+        let chunks = match self.ctx.load_object(hash.to_string()) {
+            Ok(list) => parse_chunk_list(&list).ok(),
+            Err(_) => None,
+        };
+        let Some(chunks) = chunks else {
+            self.report.corrupt.push(format!(
+                "chunk list {} does not parse ({}: {})",
+                hash,
+                label,
+                escape_path(path)
+            ));
+            return Ok(());
+        };
+
+        for (chunk, _) in chunks {
+            self.check_blob(&hex::encode(chunk), label, path);
+        }
+
+        Ok(())
+    }
+}
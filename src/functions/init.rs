@@ -142,6 +142,9 @@ fn init_project(
             snapshot_before: String::new(),
             snapshot_after: String::new(),
             remote: String::new(),
+            retention: None,
+            compression: None,
+            delta: None,
         },
         cells: HashMap::new(),
     };
@@ -188,6 +191,9 @@ fn init_cell(
         lock: String::new(),
         snapshot_after: String::new(),
         snapshot_before: String::new(),
+        retention: None,
+        compression: None,
+        delta: None,
     };
     update_project_manifest_cell(ctx, proj_ref.manifest.clone(), cell_name.clone(), new_cell)?;
     update_project_config(Path::new(&proj_ref.path), cell_name.clone(), cell_conf)?;
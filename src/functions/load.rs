@@ -1,8 +1,10 @@
 use std::{
     collections::HashMap,
-    env, fs,
-    io::Read,
-    os::unix::fs::PermissionsExt,
+    env,
+    ffi::{CString, OsStr},
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
     path::{Path, PathBuf},
     time::SystemTime,
 };
@@ -10,13 +12,22 @@ use std::{
 use chrono::{
     DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, offset::LocalResult,
 };
-use zstd::Decoder;
 
 use crate::utils::{
     DenaliToml, Errors, MainManifest, ProjectConfig, ProjectManifest, ProjectRef, Snapshot,
-    TreeStruct, context::AppContext, file_type::FileType, parse_name,
+    TreeStruct,
+    chunker::write_content,
+    context::AppContext,
+    entry_meta::EntryMeta,
+    escape::escape_path,
+    file_type::FileType,
+    parse_name,
+    pool::parallel_map,
+    tree_codec::{check_link, parse_tree},
 };
 
+use super::save::hash_file;
+
 #[derive(Debug)]
 pub struct Filter {
     pub before: Option<DateTime<Utc>>,
@@ -24,6 +35,21 @@ pub struct Filter {
     pub name: Option<String>,
 }
 
+/// What `load` restores and how.
+pub struct LoadOptions<'a> {
+    /// Snapshot to restore, the latest one matching the dates when unset.
+    pub name: Option<String>,
+    /// Restore here instead of the project or cell's own directory.
+    pub path: Option<&'a Path>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub with_config: bool,
+    /// Empty the destination first.
+    pub wipe: bool,
+    /// Only write the files that differ from the destination.
+    pub incremental: bool,
+}
+
 pub struct LocalSnapshot {
     pub name: String,
     pub timestamp: DateTime<Utc>,
@@ -164,16 +190,7 @@ fn wipe_project(ctx: &AppContext, uuid: String) -> Result<(), Errors> {
     Ok(())
 }
 
-pub fn load(
-    ctx: &AppContext,
-    project: String,
-    name: Option<String>,
-    path: Option<&Path>,
-    before: Option<String>,
-    after: Option<String>,
-    with_config: bool,
-    wipe: bool,
-) -> Result<(), Errors> {
+pub fn load(ctx: &AppContext, project: String, options: &LoadOptions) -> Result<(), Errors> {
     let (project_name, cell_name) = parse_name(project.clone())?;
 
     let manifest: MainManifest = ctx.load_main_manifest()?;
@@ -189,8 +206,8 @@ pub fn load(
         }
     }
 
-    if wipe {
-        if let Some(p) = path {
+    if options.wipe {
+        if let Some(p) = options.path {
             let config_path = Path::new(p).join(".denali.toml");
             let data = fs::read(config_path.clone()).ok();
             wipe_dir(p)?;
@@ -206,62 +223,41 @@ pub fn load(
         }
     }
 
-    let mut is_root_path = true;
-    if let Some(_) = path {
-        is_root_path = false;
-    }
-
     let project_manifest: ProjectManifest = ctx.load_project_manifest(proj.manifest.clone())?;
     let config = get_project_config(&project_manifest)?;
+    let mut files = PendingFiles::new(options.incremental);
 
     if let Some(cell) = cell_name {
         make_cell_load(
             ctx,
-            &manifest,
-            before,
-            after,
+            options,
+            proj,
             &project_manifest,
-            project_name,
             cell,
-            is_root_path,
             &config,
-            name,
-            path,
+            &mut files,
         )?;
     } else {
-        make_project_load(
-            ctx,
-            before,
-            after,
-            is_root_path,
-            name,
-            &config,
-            proj,
-            &project_manifest,
-            path,
-            with_config,
-        )?;
+        make_project_load(ctx, options, &config, proj, &project_manifest, &mut files)?;
     }
 
-    Ok(())
+    files.write(ctx)
 }
 
 fn make_project_load(
     ctx: &AppContext,
-    before: Option<String>,
-    after: Option<String>,
-    is_root_path: bool,
-    name: Option<String>,
+    options: &LoadOptions,
     config: &DenaliToml,
     proj: &ProjectRef,
     project_manifest: &ProjectManifest,
-    path: Option<&Path>,
-    with_config: bool,
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
-    let (before_cmp, after_cmp) = match (before, after) {
-        (Some(bef), Some(aft)) => (Some(parse_datetime(&bef)?), Some(parse_datetime(&aft)?)),
-        (Some(bef), None) => (Some(parse_datetime(&bef)?), None),
-        (None, Some(aft)) => (None, Some(parse_datetime(&aft)?)),
+    let is_root_path = options.path.is_none();
+    let name = options.name.clone();
+    let (before_cmp, after_cmp) = match (&options.before, &options.after) {
+        (Some(bef), Some(aft)) => (Some(parse_datetime(bef)?), Some(parse_datetime(aft)?)),
+        (Some(bef), None) => (Some(parse_datetime(bef)?), None),
+        (None, Some(aft)) => (None, Some(parse_datetime(aft)?)),
         _ => (None, None),
     };
 
@@ -326,37 +322,36 @@ fn make_project_load(
         }
     }
 
-    load_project(ctx, &project_manifest, &filter, &locks, path, with_config)?;
+    load_project(
+        ctx,
+        project_manifest,
+        &filter,
+        &locks,
+        options.path,
+        options.with_config,
+        files,
+    )?;
     Ok(())
 }
 
 fn make_cell_load(
     ctx: &AppContext,
-    manifest: &MainManifest,
-    before: Option<String>,
-    after: Option<String>,
+    options: &LoadOptions,
+    proj: &ProjectRef,
     project_manifest: &ProjectManifest,
-    project_name: String,
     cell: String,
-    is_root_path: bool,
     config: &DenaliToml,
-    name: Option<String>,
-    path: Option<&Path>,
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
-    if !manifest
-        .projects
-        .get(&project_name)
-        .ok_or(Errors::InternalError)?
-        .cells
-        .contains(&cell.clone())
-    {
+    if !proj.cells.contains(&cell) {
         return Err(Errors::ProjectNotFound(cell));
     }
 
-    let (before_cmp, after_cmp) = match (before, after) {
-        (Some(bef), Some(aft)) => (Some(parse_datetime(&bef)?), Some(parse_datetime(&aft)?)),
-        (Some(bef), None) => (Some(parse_datetime(&bef)?), None),
-        (None, Some(aft)) => (None, Some(parse_datetime(&aft)?)),
+    let is_root_path = options.path.is_none();
+    let (before_cmp, after_cmp) = match (&options.before, &options.after) {
+        (Some(bef), Some(aft)) => (Some(parse_datetime(bef)?), Some(parse_datetime(aft)?)),
+        (Some(bef), None) => (Some(parse_datetime(bef)?), None),
+        (None, Some(aft)) => (None, Some(parse_datetime(aft)?)),
         _ => (None, None),
     };
 
@@ -390,7 +385,7 @@ fn make_cell_load(
     let filter = build_filter(
         before_cmp,
         after_cmp,
-        name,
+        options.name.clone(),
         toml_bef,
         toml_aft,
         if is_root_path {
@@ -407,7 +402,14 @@ fn make_cell_load(
         },
     )?;
 
-    load_cell(ctx, &project_manifest, &filter, cell_name.to_string(), path)?;
+    load_cell(
+        ctx,
+        project_manifest,
+        &filter,
+        cell_name.to_string(),
+        options.path,
+        files,
+    )?;
     Ok(())
 }
 
@@ -428,6 +430,9 @@ fn get_project_config(project_manifest: &ProjectManifest) -> Result<DenaliToml,
                 snapshot_before: String::new(),
                 snapshot_after: String::new(),
                 remote: String::new(),
+                retention: None,
+                compression: None,
+                delta: None,
             },
             cells: HashMap::new(),
         };
@@ -441,6 +446,7 @@ fn load_cell(
     filter: &Filter,
     cell: String,
     dest: Option<&Path>,
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
     let mut newest_timestamp: Option<DateTime<Utc>> = None;
     let mut snap_meta = String::new();
@@ -475,19 +481,17 @@ fn load_cell(
         return Err(Errors::NoMatches);
     }
 
-    let meta_dir = &snap_meta[..3];
-    let meta_file = &snap_meta[3..];
-    let meta_path = ctx.snapshots_path().join(meta_dir).join(meta_file);
-    let meta_data_cmp = fs::read(meta_path)?;
-    let mut meta_data = Vec::new();
-    {
-        let mut decoder = Decoder::new(&meta_data_cmp[..])?;
-        decoder.read_to_end(&mut meta_data)?;
-    }
-
-    let meta: Snapshot = serde_json::from_slice(&meta_data)?;
+    let meta: Snapshot = ctx.load_snapshot(snap_meta)?;
 
-    restore_cell(ctx, meta.root, dest, manifest, cell, &meta.permissions)?;
+    restore_cell(
+        ctx,
+        meta.root,
+        dest,
+        manifest,
+        cell,
+        &meta.permissions,
+        files,
+    )?;
 
     Ok(())
 }
@@ -499,6 +503,7 @@ fn load_project(
     locks: &HashMap<String, Filter>,
     dest: Option<&Path>,
     with_config: bool,
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
     let mut newest_timestamp: Option<DateTime<Utc>> = None;
     let mut snap_meta = String::new();
@@ -527,18 +532,7 @@ fn load_project(
         return Err(Errors::NoMatches);
     }
 
-    let meta_dir = &snap_meta[..3];
-    let meta_file = &snap_meta[3..];
-    let meta_path = ctx.snapshots_path().join(meta_dir).join(meta_file);
-
-    let meta_data_cmp = fs::read(meta_path)?;
-    let mut meta_data = Vec::new();
-    {
-        let mut decoder = Decoder::new(&meta_data_cmp[..])?;
-        decoder.read_to_end(&mut meta_data)?;
-    }
-
-    let meta: Snapshot = serde_json::from_slice(&meta_data)?;
+    let meta: Snapshot = ctx.load_snapshot(snap_meta)?;
 
     let (destination, own_path) = match dest {
         Some(p) => (env::current_dir()?.join(p), false),
@@ -551,7 +545,15 @@ fn load_project(
         return Err(Errors::NotADir(destination));
     }
 
-    restore(ctx, meta.root, &destination, with_config, manifest)?;
+    restore(
+        ctx,
+        meta.root,
+        &destination,
+        &destination,
+        with_config,
+        manifest,
+        files,
+    )?;
 
     for (cell, lock) in locks {
         let cell_path = destination.join(cell);
@@ -565,85 +567,188 @@ fn load_project(
             lock,
             cell.to_string(),
             if own_path { None } else { Some(&cell_path) },
+            files,
         )?;
     }
     Ok(())
 }
 
-fn restore_file(
-    ctx: &AppContext,
+struct PendingFile {
     hash: String,
-    dest: &Path,
+    dest: PathBuf,
+    /// Root of the restored tree the file belongs to.
+    root: PathBuf,
     with_config: bool,
-    mode: &[u8; 4],
-) -> Result<(), Errors> {
-    let content = ctx.load_object(hash)?;
+    mode: [u8; 4],
+    meta: Option<EntryMeta>,
+}
+
+/// Files found while walking a snapshot. They are written once every directory
+/// exists, spread over `ctx.jobs` threads, and hardlinks to them are made after
+/// that. The metadata of directories, symlinks and special files is applied
+/// last, so writing the files does not bump their mtimes.
+pub struct PendingFiles {
+    files: Vec<PendingFile>,
+    links: Vec<PendingFile>,
+    metas: Vec<(PathBuf, EntryMeta)>,
+    incremental: bool,
+}
+
+impl PendingFiles {
+    /// With `incremental` set, destination files that already have the right
+    /// content are left alone.
+    pub fn new(incremental: bool) -> Self {
+        Self {
+            files: Vec::new(),
+            links: Vec::new(),
+            metas: Vec::new(),
+            incremental,
+        }
+    }
+
+    fn push(&mut self, entry: &TreeStruct, dest: &Path, root: &Path, with_config: bool) {
+        let file = PendingFile {
+            hash: hex::encode(entry.hash),
+            dest: dest.to_path_buf(),
+            root: root.to_path_buf(),
+            with_config,
+            mode: entry.mode,
+            meta: entry.meta.clone(),
+        };
+
+        if entry.meta.as_ref().is_some_and(|m| m.link.is_some()) {
+            self.links.push(file);
+        } else {
+            self.files.push(file);
+        }
+    }
+
+    fn push_meta(&mut self, path: &Path, meta: &Option<EntryMeta>) {
+        if let Some(meta) = meta {
+            self.metas.push((path.to_path_buf(), meta.clone()));
+        }
+    }
+
+    pub fn write(self, ctx: &AppContext) -> Result<(), Errors> {
+        parallel_map(ctx.jobs, &self.files, |file| {
+            restore_file(ctx, file, self.incremental)
+        })?;
+        for file in &self.links {
+            restore_link(ctx, file, self.incremental)?;
+        }
+        parallel_map(ctx.jobs, &self.metas, |(path, meta)| meta.apply(path))?;
+        Ok(())
+    }
+}
+
+fn restore_file(ctx: &AppContext, file: &PendingFile, incremental: bool) -> Result<(), Errors> {
+    let file_mode = u32::from_be_bytes(file.mode);
+    let dest = &file.dest;
 
     let file_name = dest
         .file_name()
         .ok_or(Errors::InternalError)?
         .to_string_lossy();
 
-    if file_name == ".denali.toml" && with_config {
+    if file_name == ".denali.toml" && !file.with_config {
+        return Ok(());
+    }
+
+    if !(incremental && is_unchanged(ctx, dest, &file.hash)?) {
         if dest.exists() {
-            fs::remove_file(&dest)?;
+            fs::remove_file(dest)?;
         }
-        fs::write(dest, &content)?;
+        write_content(ctx, &file.hash, file_mode, &mut File::create(dest)?)?;
+    }
+
+    // Before the permissions, as changing the owner clears setuid and setgid bits.
+    if let Some(meta) = &file.meta {
+        meta.apply(dest)?;
     }
 
     if file_name != ".denali.toml" {
-        if dest.exists() {
-            fs::remove_file(&dest)?;
-        }
-        fs::write(dest, &content)?;
-        let perms = u32::from_be_bytes(mode.clone()) & 0x0FFF;
-        let mut permissions = fs::metadata(&dest)?.permissions();
+        let perms = file_mode & 0x0FFF;
+        let mut permissions = fs::metadata(dest)?.permissions();
         permissions.set_mode(perms);
-        fs::set_permissions(&dest, permissions)?;
+        fs::set_permissions(dest, permissions)?;
     }
 
     Ok(())
 }
 
-fn parse_tree(tree: &Vec<u8>) -> Result<Vec<TreeStruct>, Errors> {
-    let mut entries = Vec::new();
+/// Hardlinks `file` to the first path of its group, or writes it as a file of
+/// its own when that path was not restored. The first path has to be a regular
+/// file inside the restored tree, also once symlinks on the way are resolved.
+fn restore_link(ctx: &AppContext, file: &PendingFile, incremental: bool) -> Result<(), Errors> {
+    let Some(link) = file.meta.as_ref().and_then(|m| m.link.as_ref()) else {
+        return restore_file(ctx, file, incremental);
+    };
+    check_link(link)?;
 
-    let mut i = 0;
-    while i < tree.len() {
-        let mode_start = i;
-        while tree[i] != b' ' {
-            i += 1;
-        }
-        let mode: [u8; 4] = tree[mode_start..i].try_into()?;
-        i += 1;
+    let first = file.root.join(link);
+    if !fs::symlink_metadata(&first).is_ok_and(|m| m.is_file()) {
+        return restore_file(ctx, file, incremental);
+    }
+    if !fs::canonicalize(&first)?.starts_with(fs::canonicalize(&file.root)?) {
+        return Err(Errors::InvalidLink(escape_path(link)));
+    }
 
-        let name_start = i;
-        while tree[i] != 0 {
-            i += 1;
+    if fs::symlink_metadata(&file.dest).is_ok() {
+        fs::remove_file(&file.dest)?;
+    }
+    fs::hard_link(&first, &file.dest)?;
+    Ok(())
+}
+
+/// Recreates a FIFO, socket or device node. Returns false when the node could not
+/// be created because the user lacks the permission, as for devices without root.
+fn restore_special(ctx: &AppContext, entry: &TreeStruct, target: &Path) -> Result<bool, Errors> {
+    if let Ok(meta) = fs::symlink_metadata(target) {
+        if meta.is_dir() {
+            fs::remove_dir_all(target)?;
+        } else {
+            fs::remove_file(target)?;
         }
-        let name = String::from_utf8_lossy(&tree[name_start..i]).to_string();
-        i += 1;
+    }
 
-        let hash: [u8; 32] = tree[i..i + 32].try_into()?;
-        i += 32;
+    let stored = ctx.load_object(hex::encode(entry.hash))?;
+    let rdev = stored.try_into().map(u64::from_be_bytes).unwrap_or(0);
+    let mode = u32::from_be_bytes(entry.mode) & 0xFFFF;
+    let c_path = CString::new(target.as_os_str().as_bytes()).map_err(|_| Errors::InternalError)?;
 
-        entries.push(TreeStruct {
-            mode,
-            name: name,
-            hash,
-        });
+    if unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EPERM) {
+            eprintln!("Can not create {}: {}", target.display(), e);
+            return Ok(false);
+        }
+        return Err(e.into());
     }
 
-    Ok(entries)
+    let mut permissions = fs::symlink_metadata(target)?.permissions();
+    permissions.set_mode(mode & 0x0FFF);
+    fs::set_permissions(target, permissions)?;
+    Ok(true)
 }
 
-fn restore_cell(
+/// Whether `dest` is a regular file whose content hashes to `hash`.
+fn is_unchanged(ctx: &AppContext, dest: &Path, hash: &str) -> Result<bool, Errors> {
+    match fs::symlink_metadata(dest) {
+        Ok(meta) if meta.is_file() => {
+            Ok(hex::encode(hash_file(ctx, dest, ctx.compression()?, None, true)?.0) == hash)
+        }
+        _ => Ok(false),
+    }
+}
+
+pub fn restore_cell(
     ctx: &AppContext,
     hash: String,
     dest: Option<&Path>,
     manifest: &ProjectManifest,
     name: String,
     mode: &[u8],
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
     let tree = ctx.load_object(hash)?;
     let entries = parse_tree(&tree)?;
@@ -685,26 +790,40 @@ fn restore_cell(
                 let mut permissions = fs::metadata(&target)?.permissions();
                 permissions.set_mode(perms);
                 fs::set_permissions(&target, permissions)?;
+                files.push_meta(&target, &entry.meta);
 
-                restore(ctx, hex::encode(entry.hash), &target, false, manifest)?;
+                restore(
+                    ctx,
+                    hex::encode(entry.hash),
+                    &target,
+                    &destination,
+                    false,
+                    manifest,
+                    files,
+                )?;
             }
             FileType::Symlink => {
-                let temp_path =
-                    String::from_utf8_lossy(&ctx.load_object(hex::encode(entry.hash))?).to_string();
-                let link = Path::new(&temp_path);
-                std::os::unix::fs::symlink(&target, link)?;
+                let stored = ctx.load_object(hex::encode(entry.hash))?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&stored), &target)?;
+                files.push_meta(&target, &entry.meta);
             }
-            FileType::Regular => {
-                restore_file(ctx, hex::encode(entry.hash), &target, false, &entry.mode)?;
+            FileType::Regular | FileType::Chunked => {
+                files.push(&entry, &target, &destination, false);
+            }
+            FileType::Fifo | FileType::CharDevice | FileType::BlockDevice | FileType::Socket => {
+                if restore_special(ctx, &entry, &target)? {
+                    files.push_meta(&target, &entry.meta);
+                }
             }
             FileType::Cell => {
                 maybe_restore_cell(
                     ctx,
                     hex::encode(entry.hash),
                     &target,
-                    &entry.name,
+                    &entry.name.to_string_lossy(),
                     manifest,
                     &entry.mode,
+                    files,
                 )?;
             }
             _ => continue,
@@ -721,6 +840,7 @@ fn maybe_restore_cell(
     name: &str,
     project: &ProjectManifest,
     mode: &[u8; 4],
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
     if let Some(_) = project.cells.get(name) {
         return Ok(());
@@ -733,17 +853,22 @@ fn maybe_restore_cell(
             project,
             name.to_string(),
             mode,
+            files,
         )?;
         return Ok(());
     }
 }
 
+/// Restores the tree `hash` into `dest`, a directory of the tree restored at
+/// `root`.
 fn restore(
     ctx: &AppContext,
     hash: String,
     dest: &Path,
+    root: &Path,
     with_config: bool,
     project: &ProjectManifest,
+    files: &mut PendingFiles,
 ) -> Result<(), Errors> {
     let tree = ctx.load_object(hash)?;
 
@@ -763,8 +888,17 @@ fn restore(
                 let mut permissions = fs::metadata(&target)?.permissions();
                 permissions.set_mode(perms);
                 fs::set_permissions(&target, permissions)?;
+                files.push_meta(&target, &entry.meta);
 
-                restore(ctx, hex::encode(entry.hash), &target, with_config, project)?;
+                restore(
+                    ctx,
+                    hex::encode(entry.hash),
+                    &target,
+                    root,
+                    with_config,
+                    project,
+                    files,
+                )?;
             }
             FileType::Symlink => {
                 if target.exists() {
@@ -775,27 +909,28 @@ fn restore(
                     }
                 }
                 let stored = ctx.load_object(hex::encode(entry.hash))?;
-                let symlink_target = PathBuf::from(String::from_utf8_lossy(&stored).to_string());
+                let symlink_target = Path::new(OsStr::from_bytes(&stored));
 
-                std::os::unix::fs::symlink(&symlink_target, &target)?;
+                std::os::unix::fs::symlink(symlink_target, &target)?;
+                files.push_meta(&target, &entry.meta);
             }
-            FileType::Regular => {
-                restore_file(
-                    ctx,
-                    hex::encode(entry.hash),
-                    &target,
-                    with_config,
-                    &entry.mode,
-                )?;
+            FileType::Regular | FileType::Chunked => {
+                files.push(&entry, &target, root, with_config);
+            }
+            FileType::Fifo | FileType::CharDevice | FileType::BlockDevice | FileType::Socket => {
+                if restore_special(ctx, &entry, &target)? {
+                    files.push_meta(&target, &entry.meta);
+                }
             }
             FileType::Cell => {
                 maybe_restore_cell(
                     ctx,
                     hex::encode(entry.hash),
                     &target,
-                    &entry.name,
+                    &entry.name.to_string_lossy(),
                     project,
                     &entry.mode,
+                    files,
                 )?;
             }
             _ => continue,
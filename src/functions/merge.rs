@@ -0,0 +1,456 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use colored::*;
+
+use crate::utils::{
+    DenaliToml, Errors, MainManifest, ProjectManifest, SNAPSHOT_FORMAT, Snapshot, Snapshots,
+    TreeStruct,
+    chunker::CHUNKED_MODE,
+    context::AppContext,
+    entry_meta::EntryMeta,
+    file_type::FileType,
+    parse_name,
+    stat_cache::StatCache,
+    text_diff::{MergeStrategy, is_binary, merge3},
+    tree_codec::{encode_tree, parse_tree},
+};
+
+use super::{
+    diff::{ChangeKind, diff_trees, print_changes},
+    load::{PendingFiles, restore_cell},
+    save::{DeltaBases, Storage, cell_storage, hash_content},
+    status::cell_status,
+};
+
+struct MergeState<'a> {
+    ctx: &'a AppContext,
+    /// Merged files are stored like `save` would store them in the cell.
+    storage: Storage,
+    bases: DeltaBases<'a>,
+    labels: (&'a str, &'a str),
+    strategy: Option<MergeStrategy>,
+    conflicts: Vec<PathBuf>,
+}
+
+/// Mode and hash of a merged tree entry.
+type MergedEntry = ([u8; 4], [u8; 32], Option<EntryMeta>);
+
+#[allow(clippy::too_many_arguments)]
+pub fn merge(
+    ctx: &AppContext,
+    project: String,
+    base: String,
+    ours: String,
+    theirs: String,
+    name: Option<String>,
+    strategy: Option<MergeStrategy>,
+    path: Option<&Path>,
+    force: bool,
+) -> Result<(), Errors> {
+    let (project_name, cell) = parse_name(project.clone())?;
+    let cell = cell.ok_or(Errors::InvalidNameFormat(project))?;
+
+    let manifest: MainManifest = ctx.load_main_manifest()?;
+    let proj_ref = manifest
+        .projects
+        .get(&project_name)
+        .ok_or_else(|| Errors::NotInitialised(PathBuf::from(&project_name)))?;
+
+    if !proj_ref.cells.contains(&cell) {
+        return Err(Errors::NotInitialised(PathBuf::from(cell)));
+    }
+
+    let uuid = proj_ref.manifest.clone();
+    let mut project_manifest: ProjectManifest = ctx.load_project_manifest(uuid.clone())?;
+
+    let name = name.unwrap_or_else(|| format!("merge-{}-{}", ours, theirs));
+    let cell_ref = project_manifest
+        .cells
+        .get(&cell)
+        .ok_or(Errors::InternalError)?;
+    if cell_ref.snapshots.contains_key(&name) {
+        return Err(Errors::SnapshotExists(name));
+    }
+
+    // Restoring into the cell overwrites whatever was not saved yet.
+    if path.is_none() && !force {
+        let mut changes = Vec::new();
+        let mut cache = StatCache::load(ctx, &uuid);
+        cell_status(
+            ctx,
+            &project_manifest,
+            &cell,
+            Path::new(""),
+            &mut changes,
+            &mut cache,
+        )?;
+        if !changes.is_empty() {
+            print_changes(&changes);
+            return Err(Errors::UnsavedChanges(changes.len()));
+        }
+    }
+
+    let config_path = Path::new(&project_manifest.source).join(".denali.toml");
+    let config: DenaliToml = toml::from_str(&fs::read_to_string(config_path)?)?;
+
+    let base_snap = load_cell_snapshot(ctx, &project_manifest, &cell, &base)?;
+    let ours_snap = load_cell_snapshot(ctx, &project_manifest, &cell, &ours)?;
+    let theirs_snap = load_cell_snapshot(ctx, &project_manifest, &cell, &theirs)?;
+
+    let mut state = MergeState {
+        ctx,
+        storage: cell_storage(ctx, &config, &uuid, &cell, &cell_ref.latest)?,
+        bases: DeltaBases::new(ctx),
+        labels: (&ours, &theirs),
+        strategy,
+        conflicts: Vec::new(),
+    };
+
+    let merged = merge_trees(
+        &mut state,
+        Some(&base_snap.root),
+        Some(&ours_snap.root),
+        Some(&theirs_snap.root),
+        Path::new(""),
+    )?;
+    let merged_root = hex::encode(merged);
+    let conflicts = state.conflicts;
+
+    let destination = match path {
+        Some(p) => env::current_dir()?.join(p),
+        None => PathBuf::from(&cell_ref.path),
+    };
+
+    let mut files = PendingFiles::new(false);
+    restore_cell(
+        ctx,
+        merged_root.clone(),
+        Some(&destination),
+        &project_manifest,
+        cell.clone(),
+        &ours_snap.permissions,
+        &mut files,
+    )?;
+    files.write(ctx)?;
+    for side in [&ours_snap.root, &theirs_snap.root] {
+        remove_merged_away(ctx, side, &merged_root, &destination)?;
+    }
+
+    if !conflicts.is_empty() {
+        println!("{}", "Merge conflicts in:".red().bold());
+        for conflict in &conflicts {
+            println!(" {}", conflict.display().to_string().red());
+        }
+        println!("Resolve them and save a new snapshot with \"denali save\"");
+        return Err(Errors::MergeConflicts(conflicts.len()));
+    }
+
+    let snapshot = Snapshot {
+        version: SNAPSHOT_FORMAT,
+        description: format!("Merge of \"{}\" and \"{}\"", ours, theirs),
+        timestamp: Utc::now(),
+        root: merged_root,
+        permissions: ours_snap.permissions,
+    };
+    let hash = hex::encode(ctx.save_snapshot(serde_json::to_vec(&snapshot)?)?);
+
+    let cell_ref = project_manifest
+        .cells
+        .get_mut(&cell)
+        .ok_or(Errors::InternalError)?;
+    cell_ref.snapshots.insert(
+        name.clone(),
+        Snapshots {
+            hash: hash.clone(),
+            is_deleted: false,
+            timestamp: Utc::now(),
+        },
+    );
+    cell_ref.latest = hash;
+    ctx.write_project_manifest(uuid, &project_manifest)?;
+
+    println!("Merged into snapshot \"{}\"", name.green());
+
+    Ok(())
+}
+
+fn load_cell_snapshot(
+    ctx: &AppContext,
+    manifest: &ProjectManifest,
+    cell: &str,
+    name: &str,
+) -> Result<Snapshot, Errors> {
+    let cell_ref = manifest.cells.get(cell).ok_or(Errors::InternalError)?;
+    match cell_ref.snapshots.get(name) {
+        Some(snapshot) if !snapshot.is_deleted => ctx.load_snapshot(snapshot.hash.clone()),
+        _ => Err(Errors::SnapshotDoesNotExist(name.to_string())),
+    }
+}
+
+/// Deletes what `side` had and the merge dropped, then the directories that
+/// leaves empty. Paths the merged tree still has, for example a file that became
+/// a directory, were just restored and stay.
+fn remove_merged_away(
+    ctx: &AppContext,
+    side: &str,
+    merged: &str,
+    destination: &Path,
+) -> Result<(), Errors> {
+    let mut changes = Vec::new();
+    diff_trees(
+        ctx,
+        &Default::default(),
+        Some(side),
+        Some(merged),
+        Path::new(""),
+        &mut changes,
+    )?;
+
+    for change in changes {
+        if change.kind != ChangeKind::Removed || in_tree(ctx, merged, &change.path)? {
+            continue;
+        }
+        let target = destination.join(&change.path);
+        if fs::symlink_metadata(&target).is_ok_and(|meta| !meta.is_dir()) {
+            fs::remove_file(&target)?;
+        }
+
+        let mut dir = change.path.parent();
+        while let Some(relative) = dir.filter(|d| !d.as_os_str().is_empty()) {
+            let path = destination.join(relative);
+            let is_empty = fs::read_dir(&path).is_ok_and(|mut e| e.next().is_none());
+            if !is_empty || in_tree(ctx, merged, relative)? {
+                break;
+            }
+            fs::remove_dir(&path)?;
+            dir = relative.parent();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the tree `root` has an entry at `path`.
+fn in_tree(ctx: &AppContext, root: &str, path: &Path) -> Result<bool, Errors> {
+    let mut tree = root.to_string();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        let entries = tree_entries(ctx, Some(&tree))?;
+        let Some(entry) = entries.get(component.as_os_str()) else {
+            return Ok(false);
+        };
+        if components.peek().is_none() {
+            return Ok(true);
+        }
+        if !is_dir(Some(entry)) {
+            return Ok(false);
+        }
+        tree = hex::encode(entry.hash);
+    }
+    Ok(true)
+}
+
+fn tree_entries(
+    ctx: &AppContext,
+    hash: Option<&str>,
+) -> Result<BTreeMap<OsString, TreeStruct>, Errors> {
+    let mut map = BTreeMap::new();
+    if let Some(hash) = hash {
+        for entry in parse_tree(&ctx.load_object(hash.to_string())?)? {
+            map.insert(entry.name.clone(), entry);
+        }
+    }
+    Ok(map)
+}
+
+fn same(a: Option<&TreeStruct>, b: Option<&TreeStruct>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.mode == b.mode && a.hash == b.hash,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn is_dir(entry: Option<&TreeStruct>) -> bool {
+    entry.is_some_and(|e| FileType::from_mode(u32::from_be_bytes(e.mode)) == FileType::Directory)
+}
+
+fn is_file(entry: Option<&TreeStruct>) -> bool {
+    entry.is_some_and(|e| FileType::from_mode(u32::from_be_bytes(e.mode)) == FileType::Regular)
+}
+
+/// Regular file, whole or chunked.
+fn is_any_file(entry: Option<&TreeStruct>) -> bool {
+    entry.is_some_and(|e| FileType::from_mode(u32::from_be_bytes(e.mode)).is_file())
+}
+
+/// Permission bits of `perms` on the file type of `content`, which may be
+/// whole on one side and chunked on the other.
+fn file_mode(perms: [u8; 4], content: [u8; 4]) -> [u8; 4] {
+    let (perms, content) = (u32::from_be_bytes(perms), u32::from_be_bytes(content));
+    ((content & 0xF000) | (perms & !0xF000)).to_be_bytes()
+}
+
+fn merge_trees(
+    state: &mut MergeState,
+    base: Option<&str>,
+    ours: Option<&str>,
+    theirs: Option<&str>,
+    prefix: &Path,
+) -> Result<[u8; 32], Errors> {
+    let base_entries = tree_entries(state.ctx, base)?;
+    let ours_entries = tree_entries(state.ctx, ours)?;
+    let theirs_entries = tree_entries(state.ctx, theirs)?;
+
+    let mut names: Vec<&OsString> = base_entries
+        .keys()
+        .chain(ours_entries.keys())
+        .chain(theirs_entries.keys())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    let mut merged: Vec<TreeStruct> = Vec::new();
+
+    for name in names {
+        let b = base_entries.get(name);
+        let o = ours_entries.get(name);
+        let t = theirs_entries.get(name);
+        let path = prefix.join(name);
+
+        let result = if same(o, t) || same(b, t) {
+            o.map(|e| (e.mode, e.hash, e.meta.clone()))
+        } else if same(b, o) {
+            t.map(|e| (e.mode, e.hash, e.meta.clone()))
+        } else {
+            merge_entry(state, b, o, t, &path)?
+        };
+
+        if let Some((mode, hash, meta)) = result {
+            merged.push(TreeStruct {
+                mode,
+                name: name.clone(),
+                hash,
+                meta,
+            });
+        }
+    }
+
+    state.ctx.save_object(encode_tree(&merged)?)
+}
+
+fn merge_entry(
+    state: &mut MergeState,
+    base: Option<&TreeStruct>,
+    ours: Option<&TreeStruct>,
+    theirs: Option<&TreeStruct>,
+    path: &Path,
+) -> Result<Option<MergedEntry>, Errors> {
+    let pick = |entry: Option<&TreeStruct>| entry.map(|e| (e.mode, e.hash, e.meta.clone()));
+
+    if let (Some(o), Some(t)) = (ours, theirs) {
+        let mode = match base {
+            Some(b) if b.mode == o.mode => t.mode,
+            _ => o.mode,
+        };
+
+        if is_dir(ours) && is_dir(theirs) {
+            let base_hash = base.filter(|_| is_dir(base)).map(|b| hex::encode(b.hash));
+            let hash = merge_trees(
+                state,
+                base_hash.as_deref(),
+                Some(&hex::encode(o.hash)),
+                Some(&hex::encode(t.hash)),
+                path,
+            )?;
+            return Ok(Some((mode, hash, o.meta.clone())));
+        }
+
+        if is_any_file(ours) && is_any_file(theirs) {
+            // Only the mode changed on one side and only the content on the other.
+            let base_hash = base.filter(|_| is_any_file(base)).map(|b| b.hash);
+            if o.hash == t.hash || base_hash == Some(t.hash) {
+                return Ok(Some((file_mode(mode, o.mode), o.hash, o.meta.clone())));
+            }
+            if base_hash == Some(o.hash) {
+                return Ok(Some((file_mode(mode, t.mode), t.hash, t.meta.clone())));
+            }
+        }
+
+        if is_file(ours) && is_file(theirs) {
+            let base_data = match base.filter(|_| is_file(base)) {
+                Some(b) => state.ctx.load_object(hex::encode(b.hash))?,
+                None => Vec::new(),
+            };
+            let ours_data = state.ctx.load_object(hex::encode(o.hash))?;
+            let theirs_data = state.ctx.load_object(hex::encode(t.hash))?;
+
+            if !is_binary(&base_data) && !is_binary(&ours_data) && !is_binary(&theirs_data) {
+                let merged = merge3(
+                    &String::from_utf8_lossy(&base_data),
+                    &String::from_utf8_lossy(&ours_data),
+                    &String::from_utf8_lossy(&theirs_data),
+                    state.labels,
+                    state.strategy,
+                );
+                if let Some((text, conflicted)) = merged {
+                    if conflicted {
+                        state.conflicts.push(path.to_path_buf());
+                    }
+                    // Merged content is new, so it gets a fresh mtime on load.
+                    return Ok(Some(store_merged(state, text.into_bytes(), mode, path)?));
+                }
+            }
+        }
+    }
+
+    match state.strategy {
+        Some(MergeStrategy::Theirs) => Ok(pick(theirs)),
+        Some(MergeStrategy::Ours) => Ok(pick(ours)),
+        Some(MergeStrategy::Union) if ours.is_none() || theirs.is_none() => {
+            Ok(pick(ours.or(theirs)))
+        }
+        _ => {
+            state.conflicts.push(path.to_path_buf());
+            Ok(pick(ours.or(theirs)))
+        }
+    }
+}
+
+/// Stores merged text the way `save` stores a file of the cell: compressed,
+/// chunked or as a delta against the file at `path` in the cell's latest
+/// snapshot, as its settings say.
+fn store_merged(
+    state: &mut MergeState,
+    content: Vec<u8>,
+    mode: [u8; 4],
+    path: &Path,
+) -> Result<MergedEntry, Errors> {
+    let base = match &state.storage.delta_base {
+        Some(root) => state.bases.find(root, path)?,
+        None => None,
+    };
+    let len = content.len() as u64;
+    let (hash, chunked) = hash_content(
+        state.ctx,
+        Cursor::new(content),
+        len,
+        state.storage.compression,
+        base.as_ref(),
+        false,
+    )?;
+    let mode = if chunked {
+        file_mode(mode, CHUNKED_MODE.to_be_bytes())
+    } else {
+        mode
+    };
+    Ok((mode, hash, None))
+}
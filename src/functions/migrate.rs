@@ -0,0 +1,174 @@
+use std::{collections::HashMap, fs};
+
+use crate::utils::{
+    Errors, SNAPSHOT_FORMAT, Snapshots,
+    chunker::{parse_chunk_list, push_chunk},
+    context::AppContext,
+    file_type::FileType,
+    journal::recover,
+    lock::LockMode,
+    store_config::StoreConfig,
+    tree_codec::{encode_tree, parse_tree},
+};
+
+/// Rewrites every object and snapshot the manifests reach in the current formats
+/// and points the manifests at the new ones. Objects are only added, so an
+/// interrupted migration leaves a readable store and can simply be run again. The
+/// store config is written last.
+pub fn migrate(ctx: &AppContext) -> Result<(), Errors> {
+    StoreConfig::check(ctx, LockMode::Shared)?;
+    if StoreConfig::load(ctx)?.is_current() {
+        println!("Store is already in the current format");
+        return Ok(());
+    }
+
+    let rolled_back = recover(ctx)?;
+    if rolled_back > 0 {
+        println!(
+            "Rolled back {} snapshots of an interrupted save",
+            rolled_back
+        );
+    }
+
+    let mut migration = Migration {
+        ctx,
+        trees: HashMap::new(),
+        snapshots: HashMap::new(),
+        objects: HashMap::new(),
+    };
+
+    let mut manifest = ctx.load_main_manifest()?;
+    for project_ref in manifest.projects.values_mut() {
+        let mut project = ctx.load_project_manifest(project_ref.manifest.clone())?;
+
+        for snapshot in project.snapshots.values_mut() {
+            migration.snapshot_ref(snapshot)?;
+        }
+        for cell in project.cells.values_mut() {
+            for snapshot in cell.snapshots.values_mut() {
+                migration.snapshot_ref(snapshot)?;
+            }
+            if !cell.latest.is_empty() {
+                cell.latest = migration.snapshot(&cell.latest)?;
+            }
+        }
+        if !project_ref.latest.is_empty() {
+            project_ref.latest = migration.snapshot(&project_ref.latest)?;
+        }
+
+        ctx.write_project_manifest(project_ref.manifest.clone(), &project)?;
+    }
+
+    for template in manifest.templates.values_mut() {
+        template.tree = migration.tree(&template.tree)?;
+    }
+
+    ctx.write_main_manifest(&manifest)?;
+    // Cached hashes may name objects of the old format.
+    let caches = ctx.stat_caches_path();
+    if caches.exists() {
+        fs::remove_dir_all(caches)?;
+    }
+    let current = StoreConfig::current();
+    StoreConfig {
+        tree_format: current.tree_format,
+        snapshot_format: current.snapshot_format,
+        object_format: current.object_format,
+        ..StoreConfig::load(ctx)?
+    }
+    .write(ctx)?;
+
+    println!(
+        "Migrated {} trees, {} snapshots and {} objects, run `denali clean` to remove the old ones",
+        migration.trees.len(),
+        migration.snapshots.len(),
+        migration.objects.len()
+    );
+    Ok(())
+}
+
+/// Old hash to new hash of everything rewritten so far.
+struct Migration<'a> {
+    ctx: &'a AppContext,
+    trees: HashMap<String, String>,
+    snapshots: HashMap<String, String>,
+    objects: HashMap<String, String>,
+}
+
+impl Migration<'_> {
+    fn snapshot_ref(&mut self, snapshot: &mut Snapshots) -> Result<(), Errors> {
+        match self.snapshot(&snapshot.hash) {
+            Ok(new) => snapshot.hash = new,
+            // Deleted snapshots may have been cleaned away already.
+            Err(Errors::ObjectNotFound(_)) if snapshot.is_deleted => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self, hash: &str) -> Result<String, Errors> {
+        if let Some(new) = self.snapshots.get(hash) {
+            return Ok(new.clone());
+        }
+
+        let mut snapshot = self.ctx.load_snapshot(hash.to_string())?;
+        snapshot.root = self.tree(&snapshot.root)?;
+        snapshot.version = SNAPSHOT_FORMAT;
+        let new = hex::encode(self.ctx.save_snapshot(serde_json::to_vec(&snapshot)?)?);
+
+        self.snapshots.insert(hash.to_string(), new.clone());
+        Ok(new)
+    }
+
+    fn tree(&mut self, hash: &str) -> Result<String, Errors> {
+        if let Some(new) = self.trees.get(hash) {
+            return Ok(new.clone());
+        }
+
+        let mut entries = parse_tree(&self.ctx.load_object(hash.to_string())?)?;
+        for entry in &mut entries {
+            let child = hex::encode(entry.hash);
+            let new = match FileType::from_mode(u32::from_be_bytes(entry.mode)) {
+                FileType::Directory => self.tree(&child)?,
+                FileType::Cell => self.snapshot(&child)?,
+                FileType::Chunked => self.chunk_list(&child)?,
+                _ => self.object(&child)?,
+            };
+            hex::decode_to_slice(new, &mut entry.hash)?;
+        }
+        let new = hex::encode(self.ctx.save_object(encode_tree(&entries)?)?);
+
+        self.trees.insert(hash.to_string(), new.clone());
+        Ok(new)
+    }
+
+    fn chunk_list(&mut self, hash: &str) -> Result<String, Errors> {
+        if let Some(new) = self.objects.get(hash) {
+            return Ok(new.clone());
+        }
+
+        let mut list = Vec::new();
+        for (chunk, size) in parse_chunk_list(&self.ctx.load_object(hash.to_string())?)? {
+            let mut new = [0u8; 32];
+            hex::decode_to_slice(self.object(&hex::encode(chunk))?, &mut new)?;
+            push_chunk(&mut list, &new, size);
+        }
+        let new = hex::encode(self.ctx.save_object(list)?);
+
+        self.objects.insert(hash.to_string(), new.clone());
+        Ok(new)
+    }
+
+    /// File contents, symlink targets and special files keep their compressed
+    /// bytes and only get renamed.
+    fn object(&mut self, hash: &str) -> Result<String, Errors> {
+        if let Some(new) = self.objects.get(hash) {
+            return Ok(new.clone());
+        }
+
+        let new = hex::encode(self.ctx.rehash_object(hash)?);
+
+        self.objects.insert(hash.to_string(), new.clone());
+        Ok(new)
+    }
+}
@@ -1,17 +1,37 @@
 pub mod check;
 pub mod clean;
+pub mod compression;
 pub mod copy;
+pub mod diff;
+pub mod fsck;
 pub mod init;
 pub mod list;
 pub mod load;
+pub mod merge;
+pub mod migrate;
+pub mod prune;
 pub mod remove;
+pub mod repack;
 pub mod save;
+pub mod stats;
+pub mod status;
+pub mod train_dict;
 
 pub use check::check;
 pub use clean::clean;
+pub use compression::compression;
 pub use copy::copy;
+pub use diff::diff;
+pub use fsck::fsck;
 pub use init::init;
 pub use list::list;
-pub use load::load;
+pub use load::{LoadOptions, load};
+pub use merge::merge;
+pub use migrate::migrate;
+pub use prune::prune;
 pub use remove::remove;
+pub use repack::repack;
 pub use save::save;
+pub use stats::stats;
+pub use status::status;
+pub use train_dict::train_dict;
@@ -0,0 +1,333 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use chrono::{DateTime, Datelike, Utc};
+use colored::*;
+
+use crate::utils::{DenaliToml, Errors, Retention, Snapshots, context::AppContext, parse_name};
+
+use super::{clean::clean, remove::get_latest_snapshot};
+
+type Bucket = fn(&DateTime<Utc>) -> String;
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+
+    /// Names of the snapshots that no rule keeps.
+    pub fn losers(&self, snapshots: &HashMap<String, Snapshots>) -> Vec<String> {
+        let mut items: Vec<(&String, &DateTime<Utc>)> = snapshots
+            .iter()
+            .filter(|(_, s)| !s.is_deleted)
+            .map(|(n, s)| (n, &s.timestamp))
+            .collect();
+        items.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        let mut keep: HashSet<&String> = HashSet::new();
+        keep.extend(items.iter().take(self.keep_last).map(|(n, _)| *n));
+
+        let buckets: [(usize, Bucket); 4] = [
+            (self.keep_hourly, |t| t.format("%Y-%m-%d %H").to_string()),
+            (self.keep_daily, |t| t.format("%Y-%m-%d").to_string()),
+            (self.keep_weekly, |t| {
+                let week = t.iso_week();
+                format!("{}-{}", week.year(), week.week())
+            }),
+            (self.keep_monthly, |t| t.format("%Y-%m").to_string()),
+        ];
+
+        for (count, bucket) in buckets {
+            let mut seen: HashSet<String> = HashSet::new();
+            for (name, timestamp) in &items {
+                if seen.len() >= count {
+                    break;
+                }
+                if seen.insert(bucket(timestamp)) {
+                    keep.insert(name);
+                }
+            }
+        }
+
+        items
+            .into_iter()
+            .filter(|(n, _)| !keep.contains(n))
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+}
+
+pub fn prune(
+    ctx: &AppContext,
+    project: String,
+    retention: Retention,
+    dry: bool,
+    and_clean: bool,
+) -> Result<(), Errors> {
+    if retention.is_empty() {
+        return Err(Errors::NoRetentionPolicy);
+    }
+
+    let (project_name, cell) = parse_name(project)?;
+    let manifest = ctx.load_main_manifest()?;
+
+    let projects: Vec<String> = if project_name == "all" && cell.is_none() {
+        let mut names: Vec<String> = manifest
+            .projects
+            .iter()
+            .filter(|(_, p)| !p.is_deleted)
+            .map(|(n, _)| n.clone())
+            .collect();
+        names.sort();
+        names
+    } else {
+        if !manifest.projects.contains_key(&project_name) {
+            return Err(Errors::ProjectNotFound(project_name));
+        }
+        vec![project_name]
+    };
+
+    for name in projects {
+        prune_project(ctx, &name, cell.as_deref(), &|_| Some(retention), dry)?;
+    }
+
+    if and_clean {
+        clean(ctx, dry)?;
+    }
+
+    Ok(())
+}
+
+/// Applies the `[retention]` rules from the project's `.denali.toml`. Cells without
+/// their own rules fall back to the rules of the root table.
+pub fn apply_retention(
+    ctx: &AppContext,
+    project_name: &str,
+    cell: Option<&str>,
+) -> Result<(), Errors> {
+    let manifest = ctx.load_main_manifest()?;
+    let proj_ref = manifest
+        .projects
+        .get(project_name)
+        .ok_or(Errors::InternalError)?;
+    let project_manifest = ctx.load_project_manifest(proj_ref.manifest.clone())?;
+    let config_path = Path::new(&project_manifest.source).join(".denali.toml");
+    let config: DenaliToml = toml::from_str(&fs::read_to_string(config_path)?)?;
+
+    if config.root.retention.is_none() && config.cells.values().all(|c| c.retention.is_none()) {
+        return Ok(());
+    }
+
+    let policy = |cell: Option<&str>| match cell {
+        Some(name) => config
+            .cells
+            .get(name)
+            .and_then(|c| c.retention)
+            .or(config.root.retention),
+        None => config.root.retention,
+    };
+
+    prune_project(ctx, project_name, cell, &policy, false)
+}
+
+fn prune_project(
+    ctx: &AppContext,
+    project_name: &str,
+    cell: Option<&str>,
+    policy: &dyn Fn(Option<&str>) -> Option<Retention>,
+    dry: bool,
+) -> Result<(), Errors> {
+    let mut manifest = ctx.load_main_manifest()?;
+    let proj_ref = manifest
+        .projects
+        .get_mut(project_name)
+        .ok_or(Errors::InternalError)?;
+    let uuid = proj_ref.manifest.clone();
+    let mut project_manifest = ctx.load_project_manifest(uuid.clone())?;
+
+    if let Some(cell_name) = cell {
+        let cell_ref = project_manifest
+            .cells
+            .get_mut(cell_name)
+            .ok_or(Errors::NotInitialised(cell_name.into()))?;
+        let label = format!("{}@{}", cell_name, project_name);
+        if prune_snapshots(&mut cell_ref.snapshots, policy(cell), &label, dry) {
+            cell_ref.latest = get_latest_snapshot(&cell_ref.snapshots).unwrap_or_default();
+            cell_ref.timestamp = Utc::now();
+        }
+    } else {
+        if prune_snapshots(
+            &mut project_manifest.snapshots,
+            policy(None),
+            project_name,
+            dry,
+        ) {
+            proj_ref.latest = get_latest_snapshot(&project_manifest.snapshots).unwrap_or_default();
+            project_manifest.timestamp = Utc::now();
+        }
+
+        let mut cells: Vec<&String> = proj_ref.cells.iter().collect();
+        cells.sort();
+        for cell_name in cells {
+            let cell_ref = project_manifest
+                .cells
+                .get_mut(cell_name)
+                .ok_or(Errors::InternalError)?;
+            if cell_ref.is_deleted {
+                continue;
+            }
+            let label = format!("{}@{}", cell_name, project_name);
+            let retention = policy(Some(cell_name));
+            if prune_snapshots(&mut cell_ref.snapshots, retention, &label, dry) {
+                cell_ref.latest = get_latest_snapshot(&cell_ref.snapshots).unwrap_or_default();
+                cell_ref.timestamp = Utc::now();
+            }
+        }
+    }
+
+    if !dry {
+        ctx.write_project_manifest(uuid, &project_manifest)?;
+        ctx.write_main_manifest(&manifest)?;
+    }
+
+    Ok(())
+}
+
+fn prune_snapshots(
+    snapshots: &mut HashMap<String, Snapshots>,
+    retention: Option<Retention>,
+    label: &str,
+    dry: bool,
+) -> bool {
+    let losers = match retention {
+        Some(retention) if !retention.is_empty() => retention.losers(snapshots),
+        _ => return false,
+    };
+
+    for name in &losers {
+        let Some(snapshot) = snapshots.get_mut(name) else {
+            continue;
+        };
+        let verb = if dry { "would prune" } else { "pruned" };
+        println!(
+            "{} {} {} ({})",
+            verb.red(),
+            label.cyan(),
+            name,
+            snapshot
+                .timestamp
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+                .dimmed()
+        );
+        if !dry {
+            snapshot.is_deleted = true;
+            snapshot.timestamp = Utc::now();
+        }
+    }
+
+    !dry && !losers.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn snapshots(times: &[(&str, &str)]) -> HashMap<String, Snapshots> {
+        times
+            .iter()
+            .map(|(name, time)| {
+                let timestamp = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")
+                    .unwrap()
+                    .and_utc();
+                let snapshot = Snapshots {
+                    hash: name.to_string(),
+                    is_deleted: false,
+                    timestamp,
+                };
+                (name.to_string(), snapshot)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keep_last_drops_the_oldest() {
+        let snaps = snapshots(&[
+            ("a", "2025-01-01 10:00"),
+            ("b", "2025-01-01 11:00"),
+            ("c", "2025-01-01 12:00"),
+            ("d", "2025-01-01 13:00"),
+        ]);
+        let retention = Retention {
+            keep_last: 2,
+            ..Default::default()
+        };
+        assert_eq!(retention.losers(&snaps), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn buckets_keep_the_newest_of_each_period() {
+        let snaps = snapshots(&[
+            ("mon-1", "2025-01-06 09:00"),
+            ("mon-2", "2025-01-06 18:00"),
+            ("tue-1", "2025-01-07 09:00"),
+            ("tue-2", "2025-01-07 09:30"),
+            ("wed", "2025-01-08 12:00"),
+        ]);
+        let daily = Retention {
+            keep_daily: 2,
+            ..Default::default()
+        };
+        assert_eq!(daily.losers(&snaps), vec!["tue-1", "mon-2", "mon-1"]);
+
+        let hourly = Retention {
+            keep_hourly: 10,
+            ..Default::default()
+        };
+        assert_eq!(hourly.losers(&snaps), vec!["tue-1"]);
+    }
+
+    #[test]
+    fn weeks_follow_iso_years() {
+        let snaps = snapshots(&[
+            ("dec", "2024-12-30 12:00"),
+            ("jan", "2025-01-02 12:00"),
+            ("prev", "2024-12-27 12:00"),
+        ]);
+        let weekly = Retention {
+            keep_weekly: 1,
+            ..Default::default()
+        };
+        assert_eq!(weekly.losers(&snaps), vec!["dec", "prev"]);
+    }
+
+    #[test]
+    fn rules_add_up_and_skip_deleted_snapshots() {
+        let mut snaps = snapshots(&[
+            ("new", "2025-03-10 12:00"),
+            ("march", "2025-03-01 12:00"),
+            ("feb", "2025-02-15 12:00"),
+            ("jan", "2025-01-15 12:00"),
+            ("gone", "2025-03-11 12:00"),
+        ]);
+        snaps.get_mut("gone").unwrap().is_deleted = true;
+
+        let retention = Retention {
+            keep_last: 2,
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        assert_eq!(retention.losers(&snaps), vec!["jan"]);
+        assert!(Retention::default().is_empty());
+        assert!(!retention.is_empty());
+    }
+}
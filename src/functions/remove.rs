@@ -2,7 +2,9 @@ use std::{collections::HashMap, fs, path::Path};
 
 use chrono::{DateTime, Utc};
 
-use crate::utils::{DenaliToml, Errors, Snapshots, context::AppContext, parse_name};
+use crate::utils::{
+    DenaliToml, Errors, Snapshots, atomic::write_atomic, context::AppContext, parse_name,
+};
 
 pub fn remove(
     ctx: &AppContext,
@@ -191,11 +193,11 @@ fn delete_project(ctx: &AppContext, project_name: String) -> Result<(), Errors>
     proj_ref.is_deleted = true;
     proj_ref.timestamp = Utc::now();
     let manifest_data = serde_json::to_vec_pretty(&manifest)?;
-    fs::write(ctx.main_manifest_path(), &manifest_data)?;
+    write_atomic(&ctx.main_manifest_path(), &manifest_data)?;
     Ok(())
 }
 
-fn get_latest_snapshot(snapshots: &HashMap<String, Snapshots>) -> Option<String> {
+pub fn get_latest_snapshot(snapshots: &HashMap<String, Snapshots>) -> Option<String> {
     let mut newest_timestamp: Option<DateTime<Utc>> = None;
     let mut snap_meta = String::new();
 
@@ -0,0 +1,81 @@
+use std::{fs, path::Path};
+
+use crate::utils::{
+    Errors,
+    context::AppContext,
+    pack::{PackEntry, read_packs, write_pack},
+};
+
+pub fn repack(ctx: &AppContext, all: bool) -> Result<(), Errors> {
+    let objects = repack_dir(&ctx.objects_path(), &ctx.object_packs_path(), all)?;
+    let snapshots = repack_dir(&ctx.snapshots_path(), &ctx.snapshot_packs_path(), all)?;
+
+    if objects == 0 && snapshots == 0 {
+        println!("Nothing to pack");
+    } else {
+        println!("Packed {} objects and {} snapshots", objects, snapshots);
+    }
+
+    Ok(())
+}
+
+/// Folds the loose files under `base` (and with `all`, the existing packs) into
+/// a single new pack, streaming each entry from where it is stored.
+fn repack_dir(base: &Path, pack_dir: &Path, all: bool) -> Result<usize, Errors> {
+    let mut entries = Vec::new();
+    let mut loose = Vec::new();
+
+    for dir_entry in fs::read_dir(base)? {
+        let dir_entry = dir_entry?;
+        if !dir_entry.file_type()?.is_dir() || dir_entry.file_name() == "pack" {
+            continue;
+        }
+
+        for file_entry in fs::read_dir(dir_entry.path())? {
+            let file_entry = file_entry?;
+            let dir_name = dir_entry.file_name().to_string_lossy().to_string();
+            let file_name = file_entry.file_name().to_string_lossy().to_string();
+
+            let mut hash = [0u8; 32];
+            if hex::decode_to_slice(format!("{}{}", dir_name, file_name), &mut hash).is_err() {
+                continue;
+            }
+
+            entries.push(PackEntry::loose(hash, file_entry.path())?);
+            loose.push(file_entry.path());
+        }
+    }
+
+    let old_packs = if all {
+        read_packs(pack_dir)?
+    } else {
+        Vec::new()
+    };
+    if loose.is_empty() && old_packs.len() < 2 {
+        return Ok(0);
+    }
+
+    let count = entries.len();
+    for pack in &old_packs {
+        entries.extend(pack.entries()?);
+    }
+
+    let new_pack = write_pack(pack_dir, entries)?;
+
+    for pack in &old_packs {
+        if pack.pack_path() != new_pack {
+            pack.remove()?;
+        }
+    }
+
+    for path in loose {
+        fs::remove_file(&path)?;
+        if let Some(dir) = path.parent()
+            && fs::read_dir(dir)?.next().is_none()
+        {
+            fs::remove_dir(dir)?;
+        }
+    }
+
+    Ok(count)
+}
@@ -2,17 +2,34 @@ use chrono::Utc;
 use globset::{Glob, GlobSet, GlobSetBuilder};
 
 use crate::utils::{
-    DenaliToml, Errors, MainManifest, ProjectManifest, Snapshot, Snapshots, TreeStruct,
-    context::AppContext, parse_name,
+    DenaliToml, Errors, MainManifest, ProjectManifest, SNAPSHOT_FORMAT, Snapshot, Snapshots,
+    TreeStruct,
+    atomic::write_atomic,
+    chunker::{CHUNK_THRESHOLD, CHUNKED_MODE, Chunker, push_chunk},
+    compression::{Compression, HEAD_LEN},
+    context::AppContext,
+    entry_meta::EntryMeta,
+    file_type::FileType,
+    journal::{Journal, recover},
+    parse_name,
+    pool::parallel_map,
+    stat_cache::StatCache,
+    tree_codec::{encode_tree, parse_tree},
 };
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{self, Read},
-    os::unix::fs::MetadataExt,
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
     path::{Path, PathBuf},
 };
 
+use super::prune::apply_retention;
+
 pub fn save(
     ctx: &AppContext,
     project: String,
@@ -21,6 +38,14 @@ pub fn save(
 ) -> Result<(), Errors> {
     let desc = description.unwrap_or("");
 
+    let rolled_back = recover(ctx)?;
+    if rolled_back > 0 {
+        println!(
+            "Rolled back {} snapshots of an interrupted save",
+            rolled_back
+        );
+    }
+
     let mut manifest: MainManifest = ctx.load_main_manifest()?;
 
     let (project, cell) = parse_name(project)?;
@@ -44,44 +69,56 @@ pub fn save(
         .clone();
 
     if cell == None {
-        let hash_list = make_project_save(
+        let proj = manifest
+            .projects
+            .get_mut(&project)
+            .ok_or(Errors::InternalError)?;
+        let latest = proj.latest.clone();
+        let mut cache = StatCache::load(ctx, &uuid);
+        let mut journal = Journal::begin(ctx)?;
+        let saved = make_project_save(
             ctx,
             uuid,
             desc,
-            &mut manifest
-                .projects
-                .get_mut(&project)
-                .ok_or(Errors::InternalError)?
-                .cells,
-        )?;
-        update_all_manifests(ctx, &name, &project, &mut manifest, hash_list)?;
+            &latest,
+            &mut proj.cells,
+            &mut journal,
+            &mut cache,
+        )
+        .and_then(|hash_list| update_all_manifests(ctx, &name, &project, &mut manifest, hash_list));
+        journal.finish(ctx, saved)?;
+        cache.write()?;
+        apply_retention(ctx, &project, None)?;
         return Ok(());
     }
 
-    save_cell(
+    let mut cache = StatCache::load(ctx, &uuid);
+    let mut journal = Journal::begin(ctx)?;
+    let saved = save_cell(
         ctx,
-        ctx.project_manifest_path(
-            manifest
-                .projects
-                .get(&project)
-                .ok_or(Errors::InternalError)?
-                .manifest
-                .clone(),
-        ),
+        &uuid,
         &name,
-        &cell.ok_or(Errors::InternalError)?,
+        &cell.clone().ok_or(Errors::InternalError)?,
         desc,
-    )?;
+        &mut journal,
+        &mut cache,
+    );
+    journal.finish(ctx, saved)?;
+    cache.write()?;
+    apply_retention(ctx, &project, cell.as_deref())?;
     Ok(())
 }
 
 fn save_cell(
     ctx: &AppContext,
-    manifest_path: PathBuf,
+    uuid: &str,
     name: &str,
     cell: &str,
     description: &str,
+    journal: &mut Journal,
+    cache: &mut StatCache,
 ) -> Result<(), Errors> {
+    let manifest_path = ctx.project_manifest_path(uuid.to_string());
     let manifest_data = fs::read(&manifest_path)?;
     let mut project_manifest: ProjectManifest = serde_json::from_slice(&manifest_data)?;
 
@@ -94,19 +131,20 @@ fn save_cell(
     let config: DenaliToml = toml::from_str(&data)?;
     let ignore = &config.cells.get(cell).ok_or(Errors::InternalError)?.ignore;
     let glob = build_globset(&ignore)?;
+    let cell_ref = project_manifest
+        .cells
+        .get(cell)
+        .ok_or(Errors::InternalError)?;
+    let storage = cell_storage(ctx, &config, uuid, cell, &cell_ref.latest)?;
 
     let hash = hash_dir(
         ctx,
-        Path::new(
-            &project_manifest
-                .cells
-                .get(cell)
-                .ok_or(Errors::InternalError)?
-                .path,
-        ),
+        Path::new(&cell_ref.path),
         &glob,
+        storage,
         description,
-        &HashMap::new(),
+        journal,
+        cache,
     )?;
 
     if let Some(cell_ref) = project_manifest.cells.get_mut(cell) {
@@ -123,12 +161,12 @@ fn save_cell(
     }
     let project_data = serde_json::to_vec_pretty(&project_manifest)?;
 
-    fs::write(manifest_path, project_data)?;
+    write_atomic(&manifest_path, &project_data)?;
 
     Ok(())
 }
 
-fn build_globset(patterns: &[String]) -> Result<GlobSet, Errors> {
+pub fn build_globset(patterns: &[String]) -> Result<GlobSet, Errors> {
     let mut builder = GlobSetBuilder::new();
     for pattern in patterns {
         builder.add(Glob::new(pattern)?);
@@ -140,28 +178,111 @@ fn make_project_save(
     ctx: &AppContext,
     uuid: String,
     description: &str,
+    latest: &str,
     cells: &mut Vec<String>,
+    journal: &mut Journal,
+    cache: &mut StatCache,
 ) -> Result<HashMap<String, ([u8; 32], [u8; 4])>, Errors> {
+    let dictionary = ctx.project_dictionary(&uuid)?;
     let proj_manifest: ProjectManifest = ctx.load_project_manifest(uuid)?;
+    let mut layout = project_layout(ctx, &proj_manifest, latest, cells)?;
+    for storage in layout.3.values_mut() {
+        storage.compression = storage.compression.with_dictionary(dictionary);
+    }
+
+    save_project(
+        ctx,
+        description,
+        Path::new(&proj_manifest.source),
+        layout,
+        journal,
+        cache,
+    )
+}
+
+/// Root ignore set, cell paths, cell ignore sets and the storage of every cell
+/// and of `root` of a project.
+pub type ProjectLayout = (
+    GlobSet,
+    HashMap<String, PathBuf>,
+    HashMap<String, GlobSet>,
+    HashMap<String, Storage>,
+);
+
+/// How the files of a cell, or of the project files outside cells, are stored.
+#[derive(Clone, Default)]
+pub struct Storage {
+    pub compression: Compression,
+    /// Root tree of the previous snapshot when deltas are on. Changed files are
+    /// stored as deltas against the file at the same path in it.
+    pub delta_base: Option<String>,
+}
+
+/// Storage of the files of `cell`, whose latest snapshot is `latest`.
+pub fn cell_storage(
+    ctx: &AppContext,
+    config: &DenaliToml,
+    uuid: &str,
+    cell: &str,
+    latest: &str,
+) -> Result<Storage, Errors> {
+    Ok(Storage {
+        compression: config
+            .compression(Some(cell), ctx.compression()?)
+            .with_dictionary(ctx.project_dictionary(uuid)?),
+        delta_base: delta_base(ctx, config.delta(Some(cell)), latest)?,
+    })
+}
+
+/// Root tree of the snapshot `latest`, if `delta` is on and it is still stored.
+fn delta_base(ctx: &AppContext, delta: bool, latest: &str) -> Result<Option<String>, Errors> {
+    if !delta || latest.is_empty() {
+        return Ok(None);
+    }
+    match ctx.load_snapshot(latest.to_string()) {
+        Ok(snapshot) => Ok(Some(snapshot.root)),
+        Err(Errors::ObjectNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// `latest` is the project's latest snapshot, the base of deltas outside cells.
+pub fn project_layout(
+    ctx: &AppContext,
+    proj_manifest: &ProjectManifest,
+    latest: &str,
+    cells: &mut Vec<String>,
+) -> Result<ProjectLayout, Errors> {
     let source_dir = &proj_manifest.source;
     let config_data = fs::read_to_string(Path::new(&source_dir).join(".denali.toml"))?;
     let config: DenaliToml = toml::from_str(&config_data)?;
     let mut cells_map: HashMap<String, PathBuf> = HashMap::new();
     let mut ignore_cells: HashMap<String, GlobSet> = HashMap::new();
-    let mut root_ignore = config.root.ignore;
+    let mut root_ignore = config.root.ignore.clone();
+    let mut storages = HashMap::new();
+    storages.insert(
+        "root".to_string(),
+        Storage {
+            compression: config.compression(None, ctx.compression()?),
+            delta_base: delta_base(ctx, config.delta(None), latest)?,
+        },
+    );
     cells.sort();
     for cell in cells {
-        let path = proj_manifest
-            .cells
-            .get(cell)
-            .ok_or(Errors::InternalError)?
-            .path
-            .clone();
+        let cell_ref = proj_manifest.cells.get(cell).ok_or(Errors::InternalError)?;
+        let path = cell_ref.path.clone();
         cells_map.insert(cell.clone(), Path::new(&path).to_path_buf());
         ignore_cells.insert(
             cell.clone(),
             build_globset(&config.cells.get(cell).ok_or(Errors::InternalError)?.ignore)?,
         );
+        storages.insert(
+            cell.clone(),
+            Storage {
+                compression: config.compression(Some(cell), ctx.compression()?),
+                delta_base: delta_base(ctx, config.delta(Some(cell)), &cell_ref.latest)?,
+            },
+        );
 
         if Path::new(&path).starts_with(&source_dir) {
             if let Some(name) = Path::new(&path).file_name() {
@@ -170,14 +291,12 @@ fn make_project_save(
         }
     }
 
-    Ok(save_project(
-        ctx,
-        description,
-        &Path::new(&proj_manifest.source),
-        &build_globset(&root_ignore)?,
+    Ok((
+        build_globset(&root_ignore)?,
         cells_map,
         ignore_cells,
-    )?)
+        storages,
+    ))
 }
 
 pub fn update_all_manifests(
@@ -241,8 +360,9 @@ pub fn update_all_manifests(
     let mani_json = serde_json::to_vec_pretty(manifest)?;
     let proj_json = serde_json::to_vec_pretty(&project_manifest)?;
 
-    fs::write(&manifest_path, mani_json)?;
-    fs::write(ctx.project_manifest_path(uuid), proj_json)?;
+    // The project manifest holds the snapshots, the main one only `latest`.
+    write_atomic(&ctx.project_manifest_path(uuid), &proj_json)?;
+    write_atomic(&manifest_path, &mani_json)?;
 
     Ok(())
 }
@@ -251,20 +371,29 @@ fn save_project(
     ctx: &AppContext,
     description: &str,
     path: &Path,
-    ignore: &GlobSet,
-    cells: HashMap<String, PathBuf>,
-    ignore_cells: HashMap<String, GlobSet>,
+    (ignore, cells, ignore_cells, mut storages): ProjectLayout,
+    journal: &mut Journal,
+    cache: &mut StatCache,
 ) -> Result<HashMap<String, ([u8; 32], [u8; 4])>, Errors> {
     let mut cells_hash: HashMap<String, ([u8; 32], [u8; 4])> = HashMap::new();
 
-    for (cell, cell_path) in &cells {
-        let hash = hash_dir(
-            ctx,
-            &cell_path,
-            ignore_cells.get(cell).ok_or(Errors::InternalError)?,
-            description,
-            &HashMap::new(),
-        )?;
+    let mut cell_list: Vec<(&String, &PathBuf)> = cells.iter().collect();
+    cell_list.sort();
+
+    // Files of all cells and the root are hashed by one worker pool.
+    let mut scans = Vec::with_capacity(cell_list.len() + 1);
+    for (cell, cell_path) in &cell_list {
+        let ignore = ignore_cells.get(*cell).ok_or(Errors::InternalError)?;
+        let storage = storages.remove(*cell).ok_or(Errors::InternalError)?;
+        scans.push(scan(ctx, cell_path, ignore, storage)?);
+    }
+    let storage = storages.remove("root").ok_or(Errors::InternalError)?;
+    scans.push(scan(ctx, path, &ignore, storage)?);
+    hash_files(ctx, &mut scans, false, cache)?;
+
+    for ((cell, cell_path), cell_scan) in cell_list.iter().zip(&scans) {
+        let tree = build_dir(ctx, cell_scan, 0, &HashMap::new(), &mut None)?;
+        let hash = save_tree_snapshot(ctx, cell_path, description, tree, journal)?;
         let meta = fs::symlink_metadata(cell_path)?;
         let perms = meta.mode() & 0x0FFF;
         let custom_mode = 0xB000 | perms;
@@ -273,7 +402,9 @@ fn save_project(
         cells_hash.insert(cell.to_string(), (hash, mode));
     }
 
-    let root_hash = hash_dir(ctx, path, ignore, description, &cells_hash)?;
+    let root_scan = scans.last().ok_or(Errors::InternalError)?;
+    let root_tree = build_dir(ctx, root_scan, 0, &cells_hash, &mut None)?;
+    let root_hash = save_tree_snapshot(ctx, path, description, root_tree, journal)?;
     cells_hash.insert("root".to_string(), (root_hash, [0, 0, 0, 0]));
     Ok(cells_hash)
 }
@@ -282,117 +413,479 @@ fn hash_dir(
     ctx: &AppContext,
     path: &Path,
     ignore: &GlobSet,
+    storage: Storage,
     description: &str,
-    cells: &HashMap<String, ([u8; 32], [u8; 4])>,
+    journal: &mut Journal,
+    cache: &mut StatCache,
 ) -> Result<[u8; 32], Errors> {
-    let hash = make_tree(ctx, path, ignore, cells, path)?;
+    let hash = make_tree(
+        ctx,
+        path,
+        ignore,
+        storage,
+        &HashMap::new(),
+        &mut None,
+        cache,
+    )?;
+    save_tree_snapshot(ctx, path, description, hash, journal)
+}
 
+fn save_tree_snapshot(
+    ctx: &AppContext,
+    path: &Path,
+    description: &str,
+    tree: [u8; 32],
+    journal: &mut Journal,
+) -> Result<[u8; 32], Errors> {
     let meta = fs::symlink_metadata(path)?;
     let mode = meta.mode().to_be_bytes();
 
     let snapshot: Snapshot = Snapshot {
+        version: SNAPSHOT_FORMAT,
         description: description.to_string(),
         timestamp: Utc::now(),
-        root: hex::encode(hash),
+        root: hex::encode(tree),
         permissions: mode,
     };
 
     let content = serde_json::to_vec(&snapshot)?;
 
-    Ok(ctx.save_snapshot(content)?)
+    let hash = ctx.save_snapshot(content)?;
+    journal.record(&hash)?;
+    Ok(hash)
 }
 
-fn build_tree(ctx: &AppContext, entries: Vec<TreeStruct>) -> Result<[u8; 32], Errors> {
-    let mut content = Vec::new();
+/// Tree objects produced by a dry run, keyed by their hex hash.
+pub type DryTrees = HashMap<String, Vec<u8>>;
 
-    for entry in entries {
-        content.extend_from_slice(&entry.mode);
-        content.push(b' ');
-        content.extend_from_slice(entry.name.as_bytes());
-        content.push(0);
-        content.extend_from_slice(&entry.hash);
+fn put_object(
+    ctx: &AppContext,
+    content: Vec<u8>,
+    compression: Compression,
+    dry: bool,
+) -> Result<[u8; 32], Errors> {
+    if dry {
+        ctx.hash_object(&content)
+    } else {
+        ctx.save_object_as(content, compression)
     }
-
-    let hash = ctx.save_object(content)?;
-    Ok(hash)
 }
 
-fn hash_file(ctx: &AppContext, path: &Path) -> Result<[u8; 32], Errors> {
-    let mut file = File::open(path)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
+fn build_tree(
+    ctx: &AppContext,
+    entries: Vec<TreeStruct>,
+    dry: &mut Option<DryTrees>,
+) -> Result<[u8; 32], Errors> {
+    let content = encode_tree(&entries)?;
+
+    if let Some(trees) = dry {
+        let hash = ctx.hash_object(&content)?;
+        trees.insert(hex::encode(hash), content);
+        return Ok(hash);
+    }
 
     let hash = ctx.save_object(content)?;
     Ok(hash)
 }
 
-pub fn make_tree(
+/// Hash of a file as `save` stores it, chunked or not. With `dry` set, nothing is
+/// written to the store. Unchunked files with a `base` may be stored as a delta
+/// against it.
+pub fn hash_file(
     ctx: &AppContext,
     path: &Path,
-    ignore: &GlobSet,
-    cells: &HashMap<String, ([u8; 32], [u8; 4])>,
-    root_path: &Path,
-) -> Result<[u8; 32], Errors> {
-    let mut entries: Vec<TreeStruct> = Vec::new();
+    compression: Compression,
+    base: Option<&[u8; 32]>,
+    dry: bool,
+) -> Result<([u8; 32], bool), Errors> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    hash_content(ctx, file, len, compression, base, dry)
+}
 
-    for (name, hash) in cells {
-        entries.push(TreeStruct {
-            mode: hash.1.clone(),
-            name: name.clone(),
-            hash: hash.0.clone(),
+/// `hash_file` for the `len` bytes of `file`, which need not be on disk.
+pub fn hash_content(
+    ctx: &AppContext,
+    mut file: impl Read + Seek,
+    len: u64,
+    compression: Compression,
+    base: Option<&[u8; 32]>,
+    dry: bool,
+) -> Result<([u8; 32], bool), Errors> {
+    if len < CHUNK_THRESHOLD {
+        let hash = match (dry, base) {
+            (true, _) => ctx.hash_object_from(&mut file)?,
+            (false, Some(base)) => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)?;
+                ctx.save_object_delta(content, base, compression)?
+            }
+            (false, None) => ctx.save_object_from(&mut file, compression)?,
+        };
+        return Ok((hash, false));
+    }
+
+    // Only the first chunk shows the format, the others follow its choice.
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    (&mut file).take(HEAD_LEN as u64).read_to_end(&mut head)?;
+    file.seek(SeekFrom::Start(0))?;
+    let compression = compression.for_content(&head);
+
+    let mut chunker = Chunker::new(file);
+    let mut list = Vec::new();
+    while let Some(chunk) = chunker.next_chunk()? {
+        let size = chunk.len() as u64;
+        let hash = put_object(ctx, chunk, compression, dry)?;
+        push_chunk(&mut list, &hash, size);
+    }
+
+    Ok((put_object(ctx, list, compression, dry)?, true))
+}
+
+fn file_mode(mode: u32, chunked: bool) -> [u8; 4] {
+    if chunked {
+        ((mode & !0xF000) | CHUNKED_MODE).to_be_bytes()
+    } else {
+        mode.to_be_bytes()
+    }
+}
+
+/// Directory tree listed by `scan`. `dirs[0]` is the scanned root.
+struct Scan {
+    root: PathBuf,
+    dirs: Vec<Vec<Scanned>>,
+    storage: Storage,
+}
+
+struct Scanned {
+    name: OsString,
+    meta: Metadata,
+    record: EntryMeta,
+    kind: ScannedKind,
+}
+
+enum ScannedKind {
+    Link(Vec<u8>),
+    /// FIFO, socket or device node, stored as its device number.
+    Special(Vec<u8>),
+    Dir(usize),
+    File {
+        path: PathBuf,
+        hash: Option<([u8; 32], bool)>,
+    },
+}
+
+/// Lists `path` level by level, with the directories of each level read in
+/// parallel. Entries keep the sorted order `make_tree` always used.
+fn scan(ctx: &AppContext, path: &Path, ignore: &GlobSet, storage: Storage) -> Result<Scan, Errors> {
+    let root = path.to_path_buf();
+    let mut dirs = vec![Vec::new()];
+
+    if !path.is_dir() {
+        if !ignore.is_match(Path::new("")) {
+            dirs[0].push(scan_entry(path)?);
+        }
+        return Ok(Scan {
+            root,
+            dirs,
+            storage,
         });
     }
 
-    if path.is_dir() {
-        if fs::read_dir(path)?.next().is_some() {
-            let mut files = fs::read_dir(path)?
-                .map(|res| res.map(|e| e.path()))
-                .collect::<Result<Vec<_>, io::Error>>()?;
-            files.sort();
-            for entry in files {
-                let name_os = entry
-                    .file_name()
-                    .ok_or(Errors::DoesntExist(entry.to_path_buf()))?;
-
-                if ignore.is_match(entry.strip_prefix(&root_path).unwrap_or(&entry)) {
-                    continue;
+    let mut level = vec![(0, path.to_path_buf())];
+    while !level.is_empty() {
+        let listed = parallel_map(ctx.jobs, &level, |(_, dir)| list_dir(dir, ignore, path))?;
+
+        let mut next = Vec::new();
+        for ((index, _), entries) in level.iter().zip(listed) {
+            for (mut entry, dir_path) in entries {
+                if let Some(dir_path) = dir_path {
+                    entry.kind = ScannedKind::Dir(dirs.len());
+                    next.push((dirs.len(), dir_path));
+                    dirs.push(Vec::new());
                 }
+                dirs[*index].push(entry);
+            }
+        }
+        level = next;
+    }
 
-                let meta = fs::symlink_metadata(entry.clone())?;
-                let mode = meta.mode().to_be_bytes();
-
-                let hash = if meta.file_type().is_symlink() {
-                    let target = fs::read_link(&entry)?;
-                    ctx.save_object(target.to_string_lossy().as_bytes().to_vec())?
-                } else if meta.is_dir() {
-                    make_tree(ctx, &entry, &ignore, &HashMap::new(), root_path)?
-                } else {
-                    hash_file(ctx, &entry)?
-                };
-
-                entries.push(TreeStruct {
-                    mode,
-                    name: name_os.to_string_lossy().to_string(),
-                    hash,
-                });
+    let mut scan = Scan {
+        root,
+        dirs,
+        storage,
+    };
+    link_hardlinks(&mut scan);
+    Ok(scan)
+}
+
+/// Points every file sharing an inode with an earlier file of the scan at that
+/// first file, by its path from the scan root, so `load` can link them together
+/// again.
+fn link_hardlinks(scan: &mut Scan) {
+    let mut groups: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+    for entry in scan.dirs.iter_mut().flatten() {
+        let ScannedKind::File { path, .. } = &entry.kind else {
+            continue;
+        };
+        if entry.meta.nlink() < 2 {
+            continue;
+        }
+
+        match groups.get(&(entry.meta.dev(), entry.meta.ino())) {
+            Some(first) => {
+                entry.record.link = first.strip_prefix(&scan.root).ok().map(Path::to_path_buf)
+            }
+            None => {
+                groups.insert((entry.meta.dev(), entry.meta.ino()), path.clone());
             }
         }
+    }
+}
+
+/// Sorted, non-ignored entries of one directory. Subdirectories come back with
+/// their path so the caller can queue them.
+fn list_dir(
+    path: &Path,
+    ignore: &GlobSet,
+    root_path: &Path,
+) -> Result<Vec<(Scanned, Option<PathBuf>)>, Errors> {
+    let mut files = fs::read_dir(path)?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    files.sort();
+
+    let mut entries = Vec::with_capacity(files.len());
+    for entry in files {
+        if ignore.is_match(entry.strip_prefix(root_path).unwrap_or(&entry)) {
+            continue;
+        }
+
+        let scanned = scan_entry(&entry)?;
+        let dir = match scanned.kind {
+            ScannedKind::Dir(_) => Some(entry),
+            _ => None,
+        };
+        entries.push((scanned, dir));
+    }
+
+    Ok(entries)
+}
+
+fn scan_entry(path: &Path) -> Result<Scanned, Errors> {
+    let name = path
+        .file_name()
+        .ok_or(Errors::DoesntExist(path.to_path_buf()))?
+        .to_os_string();
+    let meta = fs::symlink_metadata(path)?;
+    let record = EntryMeta::read(path, &meta)?;
+
+    let file_type = meta.file_type();
+    let kind = if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        ScannedKind::Link(target.into_os_string().into_vec())
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        ScannedKind::Special(meta.rdev().to_be_bytes().to_vec())
+    } else if file_type.is_fifo() || file_type.is_socket() {
+        ScannedKind::Special(Vec::new())
+    } else if meta.is_dir() {
+        ScannedKind::Dir(0)
     } else {
-        if !ignore.is_match(path.strip_prefix(&root_path).unwrap_or(&path)) {
-            let name_os = path
-                .file_name()
-                .ok_or(Errors::DoesntExist(path.to_path_buf()))?;
-            let hash = hash_file(ctx, path)?;
-            let meta = fs::symlink_metadata(path).unwrap();
-            let mode = meta.mode().to_be_bytes();
-            entries.push(TreeStruct {
-                mode,
-                name: name_os.to_string_lossy().to_string(),
-                hash,
-            });
+        ScannedKind::File {
+            path: path.to_path_buf(),
+            hash: None,
         }
     };
 
-    let hash = build_tree(ctx, entries)?;
-    Ok(hash)
+    Ok(Scanned {
+        name,
+        meta,
+        record,
+        kind,
+    })
+}
+
+/// Fills in the hash of every file in `scans`, reading the ones `cache` does not
+/// know on a pool of `ctx.jobs` threads.
+fn hash_files(
+    ctx: &AppContext,
+    scans: &mut [Scan],
+    dry: bool,
+    cache: &mut StatCache,
+) -> Result<(), Errors> {
+    let mut bases = DeltaBases::new(ctx);
+    let mut pending = Vec::new();
+    for (s, scan) in scans.iter_mut().enumerate() {
+        for (d, dir) in scan.dirs.iter_mut().enumerate() {
+            for (e, entry) in dir.iter_mut().enumerate() {
+                if let ScannedKind::File { path, hash } = &mut entry.kind {
+                    *hash = cache.lookup(ctx, path, &entry.meta)?;
+                    if hash.is_some() {
+                        continue;
+                    }
+                    let base = match &scan.storage.delta_base {
+                        Some(root) if !dry => {
+                            bases.find(root, path.strip_prefix(&scan.root).unwrap_or(path))?
+                        }
+                        _ => None,
+                    };
+                    pending.push((s, d, e, path.clone(), scan.storage.compression, base));
+                }
+            }
+        }
+    }
+
+    let hashed = parallel_map(ctx.jobs, &pending, |(_, _, _, path, compression, base)| {
+        hash_file(ctx, path, *compression, base.as_ref(), dry)
+    })?;
+
+    for ((s, d, e, path, _, _), result) in pending.into_iter().zip(hashed) {
+        let entry = &mut scans[s].dirs[d][e];
+        cache.insert(&path, &entry.meta, &result.0, result.1);
+        if let ScannedKind::File { hash, .. } = &mut entry.kind {
+            *hash = Some(result);
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the base of a changed file: the unchunked file at the same path in the
+/// previous snapshot. Trees read on the way are kept for the next files.
+pub struct DeltaBases<'a> {
+    ctx: &'a AppContext,
+    trees: HashMap<String, Vec<TreeStruct>>,
+}
+
+impl<'a> DeltaBases<'a> {
+    pub fn new(ctx: &'a AppContext) -> Self {
+        Self {
+            ctx,
+            trees: HashMap::new(),
+        }
+    }
+
+    pub fn find(&mut self, root: &str, path: &Path) -> Result<Option<[u8; 32]>, Errors> {
+        let mut tree = root.to_string();
+        let mut components = path.components().peekable();
+
+        while let Some(component) = components.next() {
+            if !self.trees.contains_key(&tree) {
+                let entries = parse_tree(&self.ctx.load_object(tree.clone())?)?;
+                self.trees.insert(tree.clone(), entries);
+            }
+            let Some(entry) = self.trees[&tree]
+                .iter()
+                .find(|entry| entry.name.as_os_str() == component.as_os_str())
+            else {
+                return Ok(None);
+            };
+
+            match (
+                FileType::from_mode(u32::from_be_bytes(entry.mode)),
+                components.peek().is_none(),
+            ) {
+                (FileType::Regular, true) => return Ok(Some(entry.hash)),
+                (FileType::Directory, false) => tree = hex::encode(entry.hash),
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn build_dir(
+    ctx: &AppContext,
+    scan: &Scan,
+    index: usize,
+    cells: &HashMap<String, ([u8; 32], [u8; 4])>,
+    dry: &mut Option<DryTrees>,
+) -> Result<[u8; 32], Errors> {
+    let mut entries: Vec<TreeStruct> = Vec::new();
+
+    let mut cell_list: Vec<_> = cells.iter().collect();
+    cell_list.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, hash) in cell_list {
+        entries.push(TreeStruct {
+            mode: hash.1,
+            name: name.into(),
+            hash: hash.0,
+            meta: None,
+        });
+    }
+
+    for entry in &scan.dirs[index] {
+        let mut mode = entry.meta.mode().to_be_bytes();
+        let hash = match &entry.kind {
+            ScannedKind::Link(target) | ScannedKind::Special(target) => {
+                put_object(ctx, target.clone(), scan.storage.compression, dry.is_some())?
+            }
+            ScannedKind::Dir(child) => build_dir(ctx, scan, *child, &HashMap::new(), dry)?,
+            ScannedKind::File { hash, .. } => {
+                let (hash, chunked) = hash.ok_or(Errors::InternalError)?;
+                mode = file_mode(entry.meta.mode(), chunked);
+                hash
+            }
+        };
+
+        entries.push(TreeStruct {
+            mode,
+            name: entry.name.clone(),
+            hash,
+            meta: Some(entry.record.clone()),
+        });
+    }
+
+    build_tree(ctx, entries, dry)
+}
+
+/// Builds the tree for `path`. With `dry` set to `Some`, nothing is written to the
+/// store and the produced tree objects are collected into the map instead. Files
+/// whose stat data matches `cache` are not read again, the rest are read on
+/// `ctx.jobs` threads.
+pub fn make_tree(
+    ctx: &AppContext,
+    path: &Path,
+    ignore: &GlobSet,
+    storage: Storage,
+    cells: &HashMap<String, ([u8; 32], [u8; 4])>,
+    dry: &mut Option<DryTrees>,
+    cache: &mut StatCache,
+) -> Result<[u8; 32], Errors> {
+    let mut scans = [scan(ctx, path, ignore, storage)?];
+    hash_files(ctx, &mut scans, dry.is_some(), cache)?;
+    build_dir(ctx, &scans[0], 0, cells, dry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::chunker::load_content;
+
+    #[test]
+    fn only_files_from_the_threshold_on_are_chunked() {
+        let (dir, ctx) = AppContext::scratch();
+
+        for (len, chunked) in [
+            (CHUNK_THRESHOLD as usize - 1, false),
+            (CHUNK_THRESHOLD as usize * 3, true),
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7919 % 251) as u8).collect();
+            let path = dir.path().join("file");
+            fs::write(&path, &data).unwrap();
+
+            let (hash, is_chunked) =
+                hash_file(&ctx, &path, Compression::default(), None, false).unwrap();
+            assert_eq!(is_chunked, chunked);
+
+            let mode = if chunked {
+                CHUNKED_MODE | 0o644
+            } else {
+                0o100644
+            };
+            assert_eq!(load_content(&ctx, &hex::encode(hash), mode).unwrap(), data);
+        }
+    }
 }
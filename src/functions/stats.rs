@@ -0,0 +1,499 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    rc::Rc,
+};
+
+use colored::*;
+use serde::Serialize;
+
+use crate::utils::{
+    Errors, ProjectManifest, Snapshots, chunker::parse_chunk_list, context::AppContext,
+    file_type::FileType, pack::read_packs, tree_codec::parse_tree,
+};
+
+#[derive(Serialize)]
+struct Stats {
+    objects: usize,
+    object_bytes: u64,
+    snapshots: usize,
+    snapshot_bytes: u64,
+    /// Everything the store holds, objects and snapshots.
+    bytes: u64,
+    /// Stored bytes of the entries live snapshots and templates reach.
+    referenced_bytes: u64,
+    /// Bytes every snapshot reaches, counted once per snapshot, over the bytes
+    /// they take in the store.
+    dedup_ratio: f64,
+    projects: Vec<ProjectStats>,
+}
+
+/// Bytes only the snapshots of a group reach, which removing them frees, and
+/// bytes they share with other snapshots or templates.
+#[derive(Serialize, Default)]
+struct Usage {
+    unique_bytes: u64,
+    shared_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ProjectStats {
+    name: String,
+    #[serde(flatten)]
+    usage: Usage,
+    snapshots: Vec<SnapshotStats>,
+    cells: Vec<CellStats>,
+}
+
+#[derive(Serialize)]
+struct CellStats {
+    name: String,
+    #[serde(flatten)]
+    usage: Usage,
+    snapshots: Vec<SnapshotStats>,
+}
+
+#[derive(Serialize)]
+struct SnapshotStats {
+    name: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Store {
+    Objects,
+    Snapshots,
+}
+
+/// A live snapshot, or a template, and the entries it reaches by id.
+struct Owner {
+    name: String,
+    snapshot: Option<u32>,
+    objects: Rc<Vec<u32>>,
+}
+
+impl Owner {
+    fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.snapshot
+            .into_iter()
+            .chain(self.objects.iter().copied())
+    }
+}
+
+struct WalkedProject {
+    name: String,
+    snapshots: Vec<Owner>,
+    cells: Vec<(String, Vec<Owner>)>,
+}
+
+/// Reports how much the store holds and which projects, cells and snapshots the
+/// space goes to. An entry is unique to a group when nothing outside it reaches
+/// the entry. Cells embedded in a project snapshot are charged to the cell, the
+/// project snapshot only gets the cell snapshots the cell no longer lists.
+pub fn stats(ctx: &AppContext, project: Option<String>, json: bool) -> Result<(), Errors> {
+    let manifest = ctx.load_main_manifest()?;
+    if let Some(project) = &project
+        && !manifest.projects.contains_key(project)
+    {
+        return Err(Errors::ProjectNotFound(project.clone()));
+    }
+
+    let mut names: Vec<&String> = manifest.projects.keys().collect();
+    names.sort();
+    let mut manifests: Vec<(&String, ProjectManifest)> = Vec::new();
+    for name in names {
+        let project_ref = &manifest.projects[name];
+        if !project_ref.is_deleted {
+            manifests.push((
+                name,
+                ctx.load_project_manifest(project_ref.manifest.clone())?,
+            ));
+        }
+    }
+
+    let mut walker = Walker::new(ctx)?;
+    for (_, project_manifest) in &manifests {
+        for cell_ref in project_manifest.cells.values() {
+            if !cell_ref.is_deleted {
+                walker.live_cells.extend(
+                    cell_ref
+                        .snapshots
+                        .values()
+                        .filter(|snapshot| !snapshot.is_deleted)
+                        .map(|snapshot| snapshot.hash.clone()),
+                );
+            }
+        }
+    }
+
+    let mut walked = Vec::new();
+    for (name, project_manifest) in &manifests {
+        let mut cell_names: Vec<&String> = project_manifest.cells.keys().collect();
+        cell_names.sort();
+        let mut cells = Vec::new();
+        for cell in cell_names {
+            let cell_ref = &project_manifest.cells[cell];
+            if !cell_ref.is_deleted {
+                cells.push((cell.clone(), walker.snapshots(&cell_ref.snapshots)?));
+            }
+        }
+
+        walked.push(WalkedProject {
+            name: name.to_string(),
+            snapshots: walker.snapshots(&project_manifest.snapshots)?,
+            cells,
+        });
+    }
+
+    let mut templates = Vec::new();
+    for (name, template_ref) in &manifest.templates {
+        templates.push(Owner {
+            name: name.clone(),
+            snapshot: None,
+            objects: walker.tree(&template_ref.tree)?,
+        });
+    }
+
+    let all_snapshots: Vec<&Owner> = walked
+        .iter()
+        .flat_map(|project| {
+            project
+                .snapshots
+                .iter()
+                .chain(project.cells.iter().flat_map(|(_, cell)| cell))
+        })
+        .collect();
+
+    let sizes = &walker.sizes;
+    let mut refs = vec![0usize; sizes.len()];
+    for owner in all_snapshots.iter().copied().chain(&templates) {
+        for id in owner.ids() {
+            refs[id as usize] += 1;
+        }
+    }
+
+    let referenced_bytes: u64 = refs
+        .iter()
+        .zip(sizes)
+        .filter(|(refs, _)| **refs > 0)
+        .map(|(_, size)| size)
+        .sum();
+    let logical_bytes: u64 = all_snapshots
+        .iter()
+        .flat_map(|owner| owner.ids())
+        .map(|id| sizes[id as usize])
+        .sum();
+    let usage = |group: &[&Owner]| {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for owner in group {
+            for id in owner.ids() {
+                *counts.entry(id).or_default() += 1;
+            }
+        }
+
+        let mut usage = Usage::default();
+        for (id, count) in counts {
+            if refs[id as usize] == count {
+                usage.unique_bytes += sizes[id as usize];
+            } else {
+                usage.shared_bytes += sizes[id as usize];
+            }
+        }
+        usage
+    };
+    let snapshot_stats = |snapshots: &[Owner]| -> Vec<SnapshotStats> {
+        snapshots
+            .iter()
+            .map(|owner| SnapshotStats {
+                name: owner.name.clone(),
+                usage: usage(&[owner]),
+            })
+            .collect()
+    };
+
+    let projects = walked
+        .iter()
+        .filter(|walked| {
+            project
+                .as_ref()
+                .is_none_or(|project| *project == walked.name)
+        })
+        .map(|walked| {
+            let group: Vec<&Owner> = walked
+                .snapshots
+                .iter()
+                .chain(walked.cells.iter().flat_map(|(_, cell)| cell))
+                .collect();
+            ProjectStats {
+                name: walked.name.clone(),
+                usage: usage(&group),
+                snapshots: snapshot_stats(&walked.snapshots),
+                cells: walked
+                    .cells
+                    .iter()
+                    .map(|(name, snapshots)| {
+                        let group: Vec<&Owner> = snapshots.iter().collect();
+                        CellStats {
+                            name: name.clone(),
+                            usage: usage(&group),
+                            snapshots: snapshot_stats(snapshots),
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    let (objects, object_bytes) = walker.stored_total(Store::Objects);
+    let (snapshots, snapshot_bytes) = walker.stored_total(Store::Snapshots);
+    let stats = Stats {
+        objects,
+        object_bytes,
+        snapshots,
+        snapshot_bytes,
+        bytes: object_bytes + snapshot_bytes,
+        referenced_bytes,
+        dedup_ratio: if referenced_bytes == 0 {
+            1.0
+        } else {
+            logical_bytes as f64 / referenced_bytes as f64
+        },
+        projects,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print_stats(&stats);
+    }
+    Ok(())
+}
+
+/// Walks snapshots like `clean` does, but numbers every entry and keeps the
+/// sorted ids each tree reaches, so trees shared between snapshots are read
+/// once and their sets are shared too.
+struct Walker<'a> {
+    ctx: &'a AppContext,
+    ids: HashMap<(Store, String), u32>,
+    /// Stored size by id, 0 for entries that are referenced but missing.
+    sizes: Vec<u64>,
+    /// Ids of everything that is stored, counted in the totals.
+    stored: Vec<(Store, u32)>,
+    trees: HashMap<String, Rc<Vec<u32>>>,
+    /// Snapshots cells list as live. Project snapshots that embed them leave
+    /// them to the cell.
+    live_cells: HashSet<String>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(ctx: &'a AppContext) -> Result<Self, Errors> {
+        let mut walker = Self {
+            ctx,
+            ids: HashMap::new(),
+            sizes: Vec::new(),
+            stored: Vec::new(),
+            trees: HashMap::new(),
+            live_cells: HashSet::new(),
+        };
+        for (store, base, packs) in [
+            (Store::Objects, ctx.objects_path(), ctx.object_packs_path()),
+            (
+                Store::Snapshots,
+                ctx.snapshots_path(),
+                ctx.snapshot_packs_path(),
+            ),
+        ] {
+            for (hash, size) in stored_sizes(&base, &packs)? {
+                let id = walker.id(store, hash);
+                walker.sizes[id as usize] = size;
+                walker.stored.push((store, id));
+            }
+        }
+        Ok(walker)
+    }
+
+    fn id(&mut self, store: Store, hash: String) -> u32 {
+        let next = self.sizes.len() as u32;
+        let id = *self.ids.entry((store, hash)).or_insert(next);
+        if id == next {
+            self.sizes.push(0);
+        }
+        id
+    }
+
+    fn stored_total(&self, store: Store) -> (usize, u64) {
+        let ids: Vec<u32> = self
+            .stored
+            .iter()
+            .filter(|(s, _)| *s == store)
+            .map(|(_, id)| *id)
+            .collect();
+        (
+            ids.len(),
+            ids.iter().map(|id| self.sizes[*id as usize]).sum(),
+        )
+    }
+
+    /// Live snapshots of a project or cell, oldest first.
+    fn snapshots(&mut self, snapshots: &HashMap<String, Snapshots>) -> Result<Vec<Owner>, Errors> {
+        let mut live: Vec<(&String, &Snapshots)> = snapshots
+            .iter()
+            .filter(|(_, snapshot)| !snapshot.is_deleted)
+            .collect();
+        live.sort_by_key(|(_, snapshot)| snapshot.timestamp);
+
+        let mut owners = Vec::with_capacity(live.len());
+        for (name, snapshot) in live {
+            let root = self.ctx.load_snapshot(snapshot.hash.clone())?.root;
+            owners.push(Owner {
+                name: name.clone(),
+                snapshot: Some(self.id(Store::Snapshots, snapshot.hash.clone())),
+                objects: self.tree(&root)?,
+            });
+        }
+        Ok(owners)
+    }
+
+    /// Sorted ids of the tree `hash` and everything below it.
+    fn tree(&mut self, hash: &str) -> Result<Rc<Vec<u32>>, Errors> {
+        if let Some(ids) = self.trees.get(hash) {
+            return Ok(ids.clone());
+        }
+
+        let mut ids = vec![self.id(Store::Objects, hash.to_string())];
+        if self.ctx.has_object(hash)? {
+            for entry in parse_tree(&self.ctx.load_object(hash.to_string())?)? {
+                let child = hex::encode(entry.hash);
+                match FileType::from_mode(u32::from_be_bytes(entry.mode)) {
+                    FileType::Directory => ids.extend(self.tree(&child)?.iter()),
+                    FileType::Cell => {
+                        if !self.live_cells.contains(&child) {
+                            let root = self.ctx.load_snapshot(child.clone())?.root;
+                            ids.push(self.id(Store::Snapshots, child));
+                            ids.extend(self.tree(&root)?.iter());
+                        }
+                    }
+                    FileType::Chunked => {
+                        ids.push(self.id(Store::Objects, child.clone()));
+                        if self.ctx.has_object(&child)? {
+                            for (chunk, _) in parse_chunk_list(&self.ctx.load_object(child)?)? {
+                                ids.push(self.id(Store::Objects, hex::encode(chunk)));
+                            }
+                        }
+                    }
+                    _ => self.blob(child, &mut ids)?,
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+
+        let ids = Rc::new(ids);
+        self.trees.insert(hash.to_string(), ids.clone());
+        Ok(ids)
+    }
+
+    /// A blob and, when it is stored as a delta, every base it needs.
+    fn blob(&mut self, hash: String, ids: &mut Vec<u32>) -> Result<(), Errors> {
+        let mut next = Some(hash);
+        while let Some(hash) = next.take() {
+            ids.push(self.id(Store::Objects, hash.clone()));
+            if self.ctx.has_object(&hash)? {
+                next = self
+                    .ctx
+                    .delta_header(&hash)?
+                    .map(|header| hex::encode(header.base));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash and stored size of every loose and packed entry of a store.
+fn stored_sizes(base: &Path, pack_dir: &Path) -> Result<HashMap<String, u64>, Errors> {
+    let mut sizes = HashMap::new();
+
+    for dir_entry in fs::read_dir(base)? {
+        let dir_entry = dir_entry?;
+        if !dir_entry.file_type()?.is_dir() || dir_entry.file_name() == "pack" {
+            continue;
+        }
+        let dir_name = dir_entry.file_name().to_string_lossy().to_string();
+        for file_entry in fs::read_dir(dir_entry.path())? {
+            let file_entry = file_entry?;
+            let file_name = file_entry.file_name().to_string_lossy().to_string();
+            sizes.insert(
+                format!("{}{}", dir_name, file_name),
+                file_entry.metadata()?.len(),
+            );
+        }
+    }
+
+    for pack in read_packs(pack_dir)? {
+        for (hash, size) in pack.sizes()? {
+            sizes.entry(hex::encode(hash)).or_insert(size);
+        }
+    }
+
+    Ok(sizes)
+}
+
+fn print_stats(stats: &Stats) {
+    println!(
+        "{} objects and {} snapshots, {} stored, {} referenced",
+        stats.objects,
+        stats.snapshots,
+        format_bytes(stats.bytes),
+        format_bytes(stats.referenced_bytes)
+    );
+    println!("Deduplication ratio: {:.2}", stats.dedup_ratio);
+
+    for project in &stats.projects {
+        println!(
+            "{} {}",
+            project.name.cyan().bold(),
+            format_usage(&project.usage)
+        );
+        for snapshot in &project.snapshots {
+            println!("  {} {}", snapshot.name, format_usage(&snapshot.usage));
+        }
+        for cell in &project.cells {
+            println!(
+                "  {} {}",
+                format!("{}@{}", cell.name, project.name).cyan(),
+                format_usage(&cell.usage)
+            );
+            for snapshot in &cell.snapshots {
+                println!("    {} {}", snapshot.name, format_usage(&snapshot.usage));
+            }
+        }
+    }
+}
+
+fn format_usage(usage: &Usage) -> String {
+    format!(
+        "{} unique, {} shared",
+        format_bytes(usage.unique_bytes),
+        format_bytes(usage.shared_bytes)
+    )
+    .dimmed()
+    .to_string()
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
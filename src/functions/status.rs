@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::utils::{
+    DenaliToml, Errors, MainManifest, ProjectManifest, context::AppContext, file_type::FileType,
+    parse_name, stat_cache::StatCache, tree_codec::parse_tree,
+};
+
+use super::{
+    diff::{Change, diff_trees, print_changes, print_patches},
+    save::{DryTrees, Storage, build_globset, make_tree, project_layout},
+};
+
+pub fn status(ctx: &AppContext, project: String, patch: bool) -> Result<(), Errors> {
+    let (project_name, cell) = parse_name(project)?;
+
+    let manifest: MainManifest = ctx.load_main_manifest()?;
+    let proj_ref = manifest
+        .projects
+        .get(&project_name)
+        .ok_or_else(|| Errors::NotInitialised(PathBuf::from(&project_name)))?;
+
+    let project_manifest = ctx.load_project_manifest(proj_ref.manifest.clone())?;
+    let mut cache = StatCache::load(ctx, &proj_ref.manifest);
+
+    let mut changes = Vec::new();
+
+    if let Some(cell_name) = cell {
+        if !proj_ref.cells.contains(&cell_name) {
+            return Err(Errors::NotInitialised(PathBuf::from(cell_name)));
+        }
+        cell_status(
+            ctx,
+            &project_manifest,
+            &cell_name,
+            Path::new(""),
+            &mut changes,
+            &mut cache,
+        )?;
+    } else {
+        project_status(
+            ctx,
+            &project_manifest,
+            &proj_ref.latest,
+            &mut proj_ref.cells.clone(),
+            &mut changes,
+            &mut cache,
+        )?;
+    }
+
+    if patch {
+        print_patches(ctx, &changes)?;
+    } else {
+        print_changes(&changes);
+    }
+
+    Ok(())
+}
+
+fn project_status(
+    ctx: &AppContext,
+    project_manifest: &ProjectManifest,
+    latest: &str,
+    cells: &mut Vec<String>,
+    changes: &mut Vec<Change>,
+    cache: &mut StatCache,
+) -> Result<(), Errors> {
+    let (root_ignore, _, _, _) = project_layout(ctx, project_manifest, latest, cells)?;
+    let source = Path::new(&project_manifest.source);
+
+    let old_root = if latest.is_empty() {
+        None
+    } else {
+        Some(ctx.load_snapshot(latest.to_string())?.root)
+    };
+
+    let mut stored_cells = HashMap::new();
+    if let Some(root) = &old_root {
+        for entry in parse_tree(&ctx.load_object(root.clone())?)? {
+            let name = entry.name.to_string_lossy().to_string();
+            if FileType::from_mode(u32::from_be_bytes(entry.mode)) == FileType::Cell
+                && cells.contains(&name)
+            {
+                stored_cells.insert(name, (entry.hash, entry.mode));
+            }
+        }
+    }
+
+    let mut dry: Option<DryTrees> = Some(HashMap::new());
+    let live_root = make_tree(
+        ctx,
+        source,
+        &root_ignore,
+        Storage::default(),
+        &stored_cells,
+        &mut dry,
+        cache,
+    )?;
+
+    let start = changes.len();
+    diff_trees(
+        ctx,
+        &dry.unwrap_or_default(),
+        old_root.as_deref(),
+        Some(&hex::encode(live_root)),
+        Path::new(""),
+        changes,
+    )?;
+    mark_live(&mut changes[start..], Path::new(""), source);
+
+    for cell in cells.iter() {
+        cell_status(ctx, project_manifest, cell, Path::new(cell), changes, cache)?;
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(())
+}
+
+pub fn cell_status(
+    ctx: &AppContext,
+    project_manifest: &ProjectManifest,
+    cell: &str,
+    prefix: &Path,
+    changes: &mut Vec<Change>,
+    cache: &mut StatCache,
+) -> Result<(), Errors> {
+    let cell_ref = project_manifest
+        .cells
+        .get(cell)
+        .ok_or(Errors::InternalError)?;
+
+    let config_path = Path::new(&project_manifest.source).join(".denali.toml");
+    let config: DenaliToml = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let ignore = build_globset(&config.cells.get(cell).ok_or(Errors::InternalError)?.ignore)?;
+
+    let old_root = if cell_ref.latest.is_empty() {
+        None
+    } else {
+        Some(ctx.load_snapshot(cell_ref.latest.clone())?.root)
+    };
+
+    let path = Path::new(&cell_ref.path);
+    let mut dry: Option<DryTrees> = Some(HashMap::new());
+    let live_root = make_tree(
+        ctx,
+        path,
+        &ignore,
+        Storage::default(),
+        &HashMap::new(),
+        &mut dry,
+        cache,
+    )?;
+
+    let start = changes.len();
+    diff_trees(
+        ctx,
+        &dry.unwrap_or_default(),
+        old_root.as_deref(),
+        Some(&hex::encode(live_root)),
+        prefix,
+        changes,
+    )?;
+    mark_live(&mut changes[start..], prefix, path);
+
+    Ok(())
+}
+
+fn mark_live(changes: &mut [Change], prefix: &Path, base: &Path) {
+    for change in changes {
+        if let Ok(rel) = change.path.strip_prefix(prefix) {
+            change.live = Some(base.join(rel));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{cmp::Reverse, collections::HashSet, fs, io::ErrorKind};
+
+use zstd::zstd_safe;
+
+use crate::utils::{
+    Errors, Snapshots,
+    atomic::write_atomic,
+    compression::{MAX_DICT_ID, MIN_DICT_ID},
+    context::AppContext,
+    file_type::FileType,
+    store_config::StoreConfig,
+    tree_codec::parse_tree,
+};
+
+/// Size of the trained dictionary, the zstd default.
+const DICT_SIZE: usize = 112 * 1024;
+
+/// Only files up to this size are sampled, larger ones compress well on their own.
+const MAX_SAMPLE: usize = 128 * 1024;
+
+/// Sampling stops once this much content is collected.
+const SAMPLE_BUDGET: usize = 32 * 1024 * 1024;
+
+const MIN_SAMPLES: usize = 16;
+
+/// Bytes of the little endian dictionary id, after the zstd dictionary magic.
+const DICT_ID_RANGE: std::ops::Range<usize> = 4..8;
+
+/// Trains a zstd dictionary on the small files of a project's snapshots, newest
+/// first, and records it in the store config. Objects saved for the project from
+/// then on are compressed with it, older objects keep their own compression.
+pub fn train_dict(ctx: &AppContext, project: String) -> Result<(), Errors> {
+    let manifest = ctx.load_main_manifest()?;
+    let uuid = manifest
+        .projects
+        .get(&project)
+        .ok_or(Errors::ProjectNotFound(project.clone()))?
+        .manifest
+        .clone();
+    let project_manifest = ctx.load_project_manifest(uuid.clone())?;
+
+    let mut snapshots: Vec<&Snapshots> = project_manifest
+        .snapshots
+        .values()
+        .chain(
+            project_manifest
+                .cells
+                .values()
+                .flat_map(|cell| cell.snapshots.values()),
+        )
+        .filter(|snapshot| !snapshot.is_deleted)
+        .collect();
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.timestamp));
+
+    let mut sampler = Sampler {
+        ctx,
+        seen: HashSet::new(),
+        samples: Vec::new(),
+        size: 0,
+    };
+    for snapshot in snapshots {
+        if sampler.size >= SAMPLE_BUDGET {
+            break;
+        }
+        sampler.snapshot(&snapshot.hash)?;
+    }
+
+    if sampler.samples.len() < MIN_SAMPLES {
+        return Err(Errors::DictionaryTraining(format!(
+            "{} has only {} small files saved",
+            project,
+            sampler.samples.len()
+        )));
+    }
+
+    let mut dictionary = zstd::dict::from_samples(&sampler.samples, DICT_SIZE)
+        .map_err(|e| Errors::DictionaryTraining(e.to_string()))?;
+    let id = zstd_safe::get_dict_id_from_dict(&dictionary)
+        .ok_or_else(|| Errors::DictionaryTraining("trained dictionary has no id".to_string()))?
+        .get();
+    let id = free_id(ctx, &mut dictionary, id)?;
+
+    let path = ctx.dictionary_path(id);
+    if !path.exists() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&path, &dictionary)?;
+    }
+
+    let mut config = StoreConfig::load(ctx)?;
+    config.dictionaries.insert(uuid, id);
+    config.write(ctx)?;
+
+    println!(
+        "Trained dictionary {} on {} files, new objects of {} are compressed with it",
+        id,
+        sampler.samples.len(),
+        project
+    );
+    Ok(())
+}
+
+/// Id under which `dictionary` can be stored. Frames only name their dictionary
+/// by id, so an id already taken by another dictionary is never overwritten:
+/// the dictionary is renumbered instead.
+fn free_id(ctx: &AppContext, dictionary: &mut [u8], mut id: u32) -> Result<u32, Errors> {
+    loop {
+        match fs::read(ctx.dictionary_path(id)) {
+            Ok(stored) if stored == dictionary => return Ok(id),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(id),
+            Err(e) => return Err(e.into()),
+        }
+
+        id = if id + 1 < MAX_DICT_ID {
+            id + 1
+        } else {
+            MIN_DICT_ID
+        };
+        dictionary[DICT_ID_RANGE].copy_from_slice(&id.to_le_bytes());
+    }
+}
+
+struct Sampler<'a> {
+    ctx: &'a AppContext,
+    /// Trees, snapshots and files already visited.
+    seen: HashSet<String>,
+    samples: Vec<Vec<u8>>,
+    size: usize,
+}
+
+impl Sampler<'_> {
+    fn snapshot(&mut self, hash: &str) -> Result<(), Errors> {
+        if !self.seen.insert(hash.to_string()) {
+            return Ok(());
+        }
+        let snapshot = self.ctx.load_snapshot(hash.to_string())?;
+        self.tree(&snapshot.root)
+    }
+
+    fn tree(&mut self, hash: &str) -> Result<(), Errors> {
+        if !self.seen.insert(hash.to_string()) {
+            return Ok(());
+        }
+
+        for entry in parse_tree(&self.ctx.load_object(hash.to_string())?)? {
+            if self.size >= SAMPLE_BUDGET {
+                break;
+            }
+            let child = hex::encode(entry.hash);
+            match FileType::from_mode(u32::from_be_bytes(entry.mode)) {
+                FileType::Directory => self.tree(&child)?,
+                FileType::Cell => self.snapshot(&child)?,
+                FileType::Regular => {
+                    if !self.seen.insert(child.clone()) {
+                        continue;
+                    }
+                    let content = self.ctx.load_object(child)?;
+                    if !content.is_empty() && content.len() <= MAX_SAMPLE {
+                        self.size += content.len();
+                        self.samples.push(content);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
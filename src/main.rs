@@ -4,6 +4,8 @@ mod remote;
 mod templates;
 mod utils;
 
+use std::time::Duration;
+
 use clap::Parser;
 use commands::{Cli, Commands, RemoteCommands, TmplCommand};
 use utils::{context::AppContext, *};
@@ -22,7 +24,15 @@ fn main() {
 
 fn run() -> Result<(), Errors> {
     let cli = Cli::parse();
-    let ctx = AppContext::new(cli.root)?;
+    let mut ctx = AppContext::new(cli.root)?;
+    ctx.lock_wait = cli.wait.map(Duration::from_secs);
+    if let Some(jobs) = cli.jobs {
+        ctx.jobs = jobs.max(1);
+    }
+    let _lock = ctx.lock(cli.command.lock_mode())?;
+    if !matches!(cli.command, Commands::Migrate) {
+        store_config::StoreConfig::check(&ctx, cli.command.lock_mode())?;
+    }
     match cli.command {
         Commands::Init {
             name,
@@ -42,21 +52,83 @@ fn run() -> Result<(), Errors> {
             after,
             with_config,
             wipe,
+            incremental,
         } => load(
             &ctx,
             project,
+            &LoadOptions {
+                name,
+                path: path.as_deref(),
+                before,
+                after,
+                with_config,
+                wipe,
+                incremental,
+            },
+        )?,
+        Commands::List { project } => list(&ctx, project)?,
+        Commands::Diff {
+            project,
+            from,
+            to,
+            patch,
+        } => diff(&ctx, project, from, to, patch)?,
+        Commands::Status { project, patch } => status(&ctx, project, patch)?,
+        Commands::Stats { project, json } => stats(&ctx, project, json)?,
+        Commands::Merge {
+            project,
+            base,
+            ours,
+            theirs,
             name,
+            strategy,
+            path,
+            force,
+        } => merge(
+            &ctx,
+            project,
+            base,
+            ours,
+            theirs,
+            name,
+            strategy,
             path.as_deref(),
-            before,
-            after,
-            with_config,
-            wipe,
+            force,
         )?,
-        Commands::List { project } => list(&ctx, project)?,
         Commands::Copy { project, path } => copy(&ctx, project, path.as_deref())?,
         Commands::Check { path } => check(&ctx, path.as_deref())?,
         Commands::Remove { project, name, all } => remove(&ctx, project, name, all)?,
         Commands::Clean { dry } => clean(&ctx, dry)?,
+        Commands::Repack { all } => repack(&ctx, all)?,
+        Commands::Fsck => fsck(&ctx)?,
+        Commands::Migrate => migrate(&ctx)?,
+        Commands::TrainDict { project } => train_dict(&ctx, project)?,
+        Commands::Compression {
+            value,
+            no_dictionary,
+        } => compression(&ctx, value, no_dictionary)?,
+        Commands::Prune {
+            project,
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            dry,
+            clean: and_clean,
+        } => prune(
+            &ctx,
+            project,
+            utils::Retention {
+                keep_last,
+                keep_hourly,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+            },
+            dry,
+            and_clean,
+        )?,
         Commands::Tmpl { sub } => match sub {
             TmplCommand::New { name, path, over } => tmpl_new(&ctx, name, path.as_deref(), over)?,
             TmplCommand::Apply {
@@ -70,9 +142,12 @@ fn run() -> Result<(), Errors> {
         },
         Commands::Sync { project, remote } => remote_sync(&ctx, project, remote)?,
         Commands::Remote { sub } => match sub {
-            RemoteCommands::Receive => remote_receive(&ctx)?,
-            RemoteCommands::Send => remote_send(&ctx)?,
-            RemoteCommands::Manifest { name } => remote_manifest(&ctx, name)?,
+            RemoteCommands::Receive { object_format } => remote_receive(&ctx, object_format)?,
+            RemoteCommands::Send { object_format } => remote_send(&ctx, object_format)?,
+            RemoteCommands::Manifest {
+                name,
+                object_format,
+            } => remote_manifest(&ctx, name, object_format)?,
             RemoteCommands::Add { name, host } => remote_add(&ctx, name, host)?,
             RemoteCommands::Remove { name } => remote_remove(&ctx, name)?,
         },
@@ -1,36 +1,56 @@
-use std::{collections::HashSet, io::Write};
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind, Read, Write},
+};
 
-use zstd::Encoder;
-
-use crate::utils::{Errors, TreeStruct, context::AppContext, file_type::FileType};
+use crate::utils::{
+    Errors, chunker::parse_chunk_list, context::AppContext, file_type::FileType,
+    tree_codec::parse_tree,
+};
 
 use super::PackType;
 
-pub fn pack_snapshot(ctx: &AppContext, hash: &[u8; 32], pack: &mut Vec<u8>) -> Result<(), Errors> {
-    let snapshot = ctx.load_snapshot(hex::encode(hash))?;
-    let bytes = serde_json::to_vec(&snapshot)?;
-    let size = (bytes.len() as u64).to_be_bytes();
-    pack.push(PackType::Snapshot.as_byte());
-    pack.extend_from_slice(hash);
-    pack.extend_from_slice(&size);
-    pack.extend_from_slice(&bytes);
+/// Both ends have to name objects the same way, or every transfer would fail its
+/// hash check.
+pub fn check_object_format(ctx: &AppContext, object_format: u32) -> Result<(), Errors> {
+    let local = ctx.object_format()?;
+    if local != object_format {
+        return Err(Errors::ObjectFormatMismatch(local, object_format));
+    }
     Ok(())
 }
 
-pub fn pack_object(ctx: &AppContext, hash: &[u8; 32], pack: &mut Vec<u8>) -> Result<(), Errors> {
-    let bytes = ctx.load_object(hex::encode(hash))?;
+/// Sends the snapshot as stored, so it arrives with the bytes its hash covers.
+pub fn pack_snapshot(
+    ctx: &AppContext,
+    hash: &[u8; 32],
+    pack: &mut impl Write,
+) -> Result<(), Errors> {
+    let bytes = ctx.load_snapshot_bytes(&hex::encode(hash))?;
     let size = (bytes.len() as u64).to_be_bytes();
-    pack.push(PackType::Object.as_byte());
-    pack.extend_from_slice(hash);
-    pack.extend_from_slice(&size);
-    pack.extend_from_slice(&bytes);
+    pack.write_all(&[PackType::Snapshot.as_byte()])?;
+    pack.write_all(hash)?;
+    pack.write_all(&size)?;
+    pack.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Streams an object into the pack. The object is decompressed twice, once to
+/// learn its size for the header and once for the body.
+pub fn pack_object(ctx: &AppContext, hash: &[u8; 32], pack: &mut impl Write) -> Result<(), Errors> {
+    let hash_str = hex::encode(hash);
+    let size = io::copy(&mut ctx.open_object(&hash_str)?, &mut io::sink())?;
+    pack.write_all(&[PackType::Object.as_byte()])?;
+    pack.write_all(hash)?;
+    pack.write_all(&size.to_be_bytes())?;
+    io::copy(&mut ctx.open_object(&hash_str)?, pack)?;
     Ok(())
 }
 
 pub fn pack_tree(
     ctx: &AppContext,
     hash: String,
-    pack: &mut Vec<u8>,
+    pack: &mut impl Write,
     copied: &mut HashSet<String>,
 ) -> Result<(), Errors> {
     let tree = ctx.load_object(hash.clone())?;
@@ -59,6 +79,18 @@ pub fn pack_tree(
                     }
                 }
             }
+            FileType::Chunked => {
+                if !copied.contains(&hash_str) {
+                    let list = ctx.load_object(hash_str.clone())?;
+                    for (chunk, _) in parse_chunk_list(&list)? {
+                        if copied.insert(hex::encode(chunk)) {
+                            pack_object(ctx, &chunk, pack)?;
+                        }
+                    }
+                    pack_object(ctx, &entry.hash, pack)?;
+                    copied.insert(hash_str);
+                }
+            }
             _ => {
                 if !copied.contains(&hash_str) {
                     pack_object(ctx, &entry.hash, pack)?;
@@ -76,88 +108,60 @@ pub fn pack_tree(
     Ok(())
 }
 
-fn parse_tree(tree: &Vec<u8>) -> Result<Vec<TreeStruct>, Errors> {
-    let mut entries = Vec::new();
-
-    let mut i = 0;
-    while i < tree.len() {
-        let mode_start = i;
-        while tree[i] != b' ' {
-            i += 1;
-        }
-        let mode: [u8; 4] = tree[mode_start..i].try_into()?;
-        i += 1;
-
-        let name_start = i;
-        while tree[i] != 0 {
-            i += 1;
-        }
-        let name = String::from_utf8_lossy(&tree[name_start..i]).to_string();
-        i += 1;
-
-        let hash: [u8; 32] = tree[i..i + 32].try_into()?;
-        i += 32;
-
-        entries.push(TreeStruct {
-            mode,
-            name: name,
-            hash,
-        });
+/// Type byte of the next record, or `None` at the end of the stream.
+pub fn read_type(reader: &mut impl Read) -> Result<Option<PackType>, Errors> {
+    let mut byte = [0u8; 1];
+    match reader.read_exact(&mut byte) {
+        Ok(()) => Ok(PackType::from_byte(byte[0])),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
     }
+}
 
-    Ok(entries)
+pub fn read_size(reader: &mut impl Read) -> Result<u64, Errors> {
+    let mut size = [0u8; 8];
+    reader.read_exact(&mut size)?;
+    Ok(u64::from_be_bytes(size))
 }
 
-pub fn unpack_object(ctx: &AppContext, content: &[u8], pointer: &mut u64) -> Result<(), Errors> {
-    let mut i = *pointer as usize;
-    let hash: [u8; 32] = content[i..i + 32].try_into()?;
-    i += 32;
-    let size = u64::from_be_bytes(content[i..i + 8].try_into()?);
-    i += 8;
-    let bytes = &content[i..i + size as usize];
-    i += size as usize;
-    *pointer = i as u64;
-
-    let mut compressed = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed, 3)?;
-        encoder.write_all(&bytes)?;
-        encoder.finish()?;
+pub fn read_record(reader: &mut impl Read) -> Result<Vec<u8>, Errors> {
+    let size = read_size(reader)?;
+    let mut data = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(Errors::Io(ErrorKind::UnexpectedEof.into()));
     }
+    Ok(data)
+}
 
-    let check = *blake3::hash(&compressed).as_bytes();
+pub fn unpack_object(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash)?;
+    let size = read_size(reader)?;
+
+    let mut body = reader.take(size);
+    let check = ctx.save_object_from(&mut body, ctx.compression()?)?;
+    if body.limit() != 0 {
+        return Err(Errors::Io(ErrorKind::UnexpectedEof.into()));
+    }
 
     if hash != check {
         return Err(Errors::HashMismatch);
     }
 
-    ctx.save_object(bytes.to_vec())?;
     Ok(())
 }
 
-pub fn unpack_snapshot(ctx: &AppContext, content: &[u8], pointer: &mut u64) -> Result<(), Errors> {
-    let mut i = *pointer as usize;
-    let hash: [u8; 32] = content[i..i + 32].try_into()?;
-    i += 32;
-    let size = u64::from_be_bytes(content[i..i + 8].try_into()?);
-    i += 8;
-    let bytes = &content[i..i + size as usize];
-    i += size as usize;
-    *pointer = i as u64;
-
-    let mut compressed = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed, 3)?;
-        encoder.write_all(&bytes)?;
-        encoder.finish()?;
-    }
+pub fn unpack_snapshot(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash)?;
+    let bytes = read_record(reader)?;
 
-    let check = *blake3::hash(&compressed).as_bytes();
+    let check = ctx.save_snapshot(bytes)?;
 
     if hash != check {
         return Err(Errors::HashMismatch);
     }
 
-    ctx.save_snapshot(bytes.to_vec())?;
     Ok(())
 }
@@ -7,22 +7,36 @@ use std::{
 use uuid::Uuid;
 use zstd::Encoder;
 
-use crate::utils::{Errors, context::AppContext};
+use crate::utils::{Errors, MainManifest, context::AppContext};
 
-use super::PackType;
+use super::{PackType, helpers::check_object_format};
 
-pub fn remote_manifest(ctx: &AppContext, project: String) -> Result<(), Errors> {
-    ctx.make_root_dir()?;
-    let manifest = ctx.load_main_manifest()?;
-    let mut pack: Vec<u8> = Vec::new();
+pub fn remote_manifest(
+    ctx: &AppContext,
+    project: String,
+    object_format: u32,
+) -> Result<(), Errors> {
+    check_object_format(ctx, object_format)?;
+    // Runs under a shared lock, so a store that does not exist yet is left to
+    // `receive` to create.
+    let manifest = if ctx.main_manifest_path().exists() {
+        ctx.load_main_manifest()?
+    } else {
+        MainManifest {
+            projects: HashMap::new(),
+            templates: HashMap::new(),
+            remotes: HashMap::new(),
+        }
+    };
+    let mut pack = Encoder::new(stdout().lock(), ctx.compression()?.stream_level())?;
 
     if project == "all" {
         let refs = manifest.projects.clone();
         let bytes = serde_json::to_vec(&refs)?;
         let size = bytes.len() as u64;
-        pack.push(PackType::Main.as_byte());
-        pack.extend_from_slice(&size.to_be_bytes());
-        pack.extend_from_slice(&bytes);
+        pack.write_all(&[PackType::Main.as_byte()])?;
+        pack.write_all(&size.to_be_bytes())?;
+        pack.write_all(&bytes)?;
 
         for (_name, proj_ref) in manifest.projects {
             let proj_manifest = ctx.load_project_manifest(proj_ref.manifest.clone())?;
@@ -30,10 +44,10 @@ pub fn remote_manifest(ctx: &AppContext, project: String) -> Result<(), Errors>
             let id = Uuid::from_str(&proj_ref.manifest)?;
             let size = data.len() as u64;
 
-            pack.push(PackType::Project.as_byte());
-            pack.extend_from_slice(id.as_bytes());
-            pack.extend_from_slice(&size.to_be_bytes());
-            pack.extend_from_slice(&data);
+            pack.write_all(&[PackType::Project.as_byte()])?;
+            pack.write_all(id.as_bytes())?;
+            pack.write_all(&size.to_be_bytes())?;
+            pack.write_all(&data)?;
         }
     } else {
         if let Ok(proj_ref) = ctx.load_project_manifest(project.clone()) {
@@ -45,30 +59,23 @@ pub fn remote_manifest(ctx: &AppContext, project: String) -> Result<(), Errors>
             main.insert(proj_ref.name.clone(), main_ref);
             let bytes = serde_json::to_vec(&main)?;
             let size = bytes.len() as u64;
-            pack.push(PackType::Main.as_byte());
-            pack.extend_from_slice(&size.to_be_bytes());
-            pack.extend_from_slice(&bytes);
+            pack.write_all(&[PackType::Main.as_byte()])?;
+            pack.write_all(&size.to_be_bytes())?;
+            pack.write_all(&bytes)?;
             let data = serde_json::to_vec(&proj_ref)?;
             let id = Uuid::from_str(&project)?;
             let size = data.len() as u64;
 
-            pack.push(PackType::Project.as_byte());
-            pack.extend_from_slice(id.as_bytes());
-            pack.extend_from_slice(&size.to_be_bytes());
-            pack.extend_from_slice(&data);
+            pack.write_all(&[PackType::Project.as_byte()])?;
+            pack.write_all(id.as_bytes())?;
+            pack.write_all(&size.to_be_bytes())?;
+            pack.write_all(&data)?;
         } else {
-            pack.push(PackType::NotFound.as_byte());
+            pack.write_all(&[PackType::NotFound.as_byte()])?;
         }
     }
 
-    let mut compressed = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed, 3)?;
-        encoder.write_all(&pack)?;
-        encoder.finish()?;
-    }
-
-    stdout().write_all(&compressed)?;
+    pack.finish()?.flush()?;
 
     Ok(())
 }
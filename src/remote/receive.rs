@@ -10,47 +10,34 @@ use crate::utils::{Errors, ProjectManifest, ProjectRef, context::AppContext};
 
 use super::{
     PackType,
-    helpers::{unpack_object, unpack_snapshot},
+    helpers::{check_object_format, read_record, read_type, unpack_object, unpack_snapshot},
 };
 
-pub fn remote_receive(ctx: &AppContext) -> Result<(), Errors> {
-    let mut input = Vec::new();
-    stdin().read_to_end(&mut input)?;
+pub fn remote_receive(ctx: &AppContext, object_format: u32) -> Result<(), Errors> {
+    check_object_format(ctx, object_format)?;
+    ctx.make_root_dir()?;
+    let mut decoder = Decoder::new(stdin().lock())?;
 
-    let mut content = Vec::new();
-    {
-        let mut decoder = Decoder::new(&input[..])?;
-        decoder.read_to_end(&mut content)?;
-    }
-
-    unpack(ctx, &content)?;
+    unpack(ctx, &mut decoder)?;
 
     Ok(())
 }
 
-fn unpack(ctx: &AppContext, content: &Vec<u8>) -> Result<(), Errors> {
-    let mut pointer: u64 = 0;
-    while (pointer as usize) < content.len() {
-        let mode = PackType::from_byte(content[pointer as usize]);
-        pointer += 1;
-        if let Some(mode) = mode {
-            match mode {
-                PackType::Object => unpack_object(ctx, content, &mut pointer)?,
-                PackType::Snapshot => unpack_snapshot(ctx, content, &mut pointer)?,
-                PackType::Main => unpack_main(ctx, content, &mut pointer)?,
-                PackType::Project => unpack_project(ctx, content, &mut pointer)?,
-                _ => {}
-            }
-        } else {
-            break;
+fn unpack(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
+    while let Some(mode) = read_type(reader)? {
+        match mode {
+            PackType::Object => unpack_object(ctx, reader)?,
+            PackType::Snapshot => unpack_snapshot(ctx, reader)?,
+            PackType::Main => unpack_main(ctx, reader)?,
+            PackType::Project => unpack_project(ctx, reader)?,
+            _ => {}
         }
     }
 
     Ok(())
 }
 
-fn unpack_main(ctx: &AppContext, content: &Vec<u8>, pointer: &mut u64) -> Result<(), Errors> {
-    let mut i = *pointer as usize;
+fn unpack_main(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
     let mut main_manifest = ctx.load_main_manifest()?;
     let uuid_to_name: HashMap<String, String> = main_manifest
         .projects
@@ -58,11 +45,8 @@ fn unpack_main(ctx: &AppContext, content: &Vec<u8>, pointer: &mut u64) -> Result
         .map(|(name, proj)| (proj.manifest.clone(), name.clone()))
         .collect();
 
-    let size = u64::from_be_bytes(content[i..i + 8].try_into()?);
-    i += 8;
     let incoming_projects: HashMap<String, ProjectRef> =
-        serde_json::from_slice(&content[i..i + size as usize])?;
-    i += size as usize;
+        serde_json::from_slice(&read_record(reader)?)?;
 
     for (name, proj_ref) in incoming_projects {
         if let Some(local_name) = uuid_to_name.get(&proj_ref.manifest) {
@@ -77,28 +61,19 @@ fn unpack_main(ctx: &AppContext, content: &Vec<u8>, pointer: &mut u64) -> Result
         }
     }
 
-    *pointer = i as u64;
-
     ctx.write_main_manifest(&main_manifest)?;
 
     Ok(())
 }
 
-fn unpack_project(ctx: &AppContext, content: &Vec<u8>, pointer: &mut u64) -> Result<(), Errors> {
-    let mut i = *pointer as usize;
-
-    let uuid = Uuid::from_bytes(content[i..i + 16].try_into()?);
-    i += 16;
-
-    let size = u64::from_be_bytes(content[i..i + 8].try_into()?);
-    i += 8;
-
-    let data = &content[i..i + size as usize];
-    i += size as usize;
+fn unpack_project(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
+    let mut uuid = [0u8; 16];
+    reader.read_exact(&mut uuid)?;
+    let uuid = Uuid::from_bytes(uuid);
 
-    *pointer = i as u64;
+    let data = read_record(reader)?;
 
-    let manifest: ProjectManifest = serde_json::from_slice(data)?;
+    let manifest: ProjectManifest = serde_json::from_slice(&data)?;
 
     ctx.write_project_manifest(uuid.to_string(), &manifest)?;
 
@@ -1,45 +1,38 @@
 use std::{
     collections::HashSet,
-    io::{Read, Write, stdin, stdout},
+    io::{ErrorKind, Read, Write, stdin, stdout},
 };
 
 use zstd::{Decoder, Encoder};
 
 use crate::utils::{Errors, context::AppContext};
 
-use super::helpers::{pack_snapshot, pack_tree};
+use super::helpers::{check_object_format, pack_snapshot, pack_tree};
 
-pub fn remote_send(ctx: &AppContext) -> Result<(), Errors> {
-    let mut input = Vec::new();
-    stdin().read_to_end(&mut input)?;
-
-    let mut content = Vec::new();
-    {
-        let mut decoder = Decoder::new(&input[..])?;
-        decoder.read_to_end(&mut content)?;
-    }
-
-    let mut pack = Vec::new();
-    pack_snapshots(ctx, content, &mut pack)?;
-
-    let mut compressed = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed, 3)?;
-        encoder.write_all(&pack)?;
-        encoder.finish()?;
-    }
-
-    stdout().write_all(&compressed)?;
+pub fn remote_send(ctx: &AppContext, object_format: u32) -> Result<(), Errors> {
+    check_object_format(ctx, object_format)?;
+    let mut decoder = Decoder::new(stdin().lock())?;
+    let mut encoder = Encoder::new(stdout().lock(), ctx.compression()?.stream_level())?;
+    pack_snapshots(ctx, &mut decoder, &mut encoder)?;
+    encoder.finish()?.flush()?;
 
     Ok(())
 }
 
-fn pack_snapshots(ctx: &AppContext, snapshots: Vec<u8>, send: &mut Vec<u8>) -> Result<(), Errors> {
-    let mut i = 0;
+/// Packs the snapshots `snapshots` names, one 32 byte hash after the other.
+fn pack_snapshots(
+    ctx: &AppContext,
+    snapshots: &mut impl Read,
+    send: &mut impl Write,
+) -> Result<(), Errors> {
     let mut copied = HashSet::new();
-    while i < snapshots.len() {
-        let hash: [u8; 32] = snapshots[i..i + 32].try_into()?;
-        i += 32;
+    let mut hash = [0u8; 32];
+    loop {
+        match snapshots.read_exact(&mut hash) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
         let hash_str = hex::encode(hash);
         if !copied.contains(&hash_str) {
             pack_snapshot(ctx, &hash, send)?;
@@ -3,7 +3,6 @@ use std::{
     io::{Read, Write},
     process::{Command, Stdio},
     str::FromStr,
-    usize,
 };
 
 use chrono::{DateTime, Utc};
@@ -11,12 +10,13 @@ use uuid::Uuid;
 use zstd::{Decoder, Encoder};
 
 use crate::utils::{
-    CellRef, Errors, MainManifest, ProjectManifest, ProjectRef, Snapshots, context::AppContext,
+    CellRef, Errors, MainManifest, ProjectManifest, ProjectRef, Snapshots,
+    context::{AppContext, OBJECT_FORMAT},
 };
 
 use super::{
     PackType,
-    helpers::{pack_snapshot, pack_tree, unpack_object, unpack_snapshot},
+    helpers::{pack_snapshot, pack_tree, read_record, read_type, unpack_object, unpack_snapshot},
 };
 
 pub fn remote_sync(ctx: &AppContext, project: String, remote: String) -> Result<(), Errors> {
@@ -46,112 +46,77 @@ pub fn remote_sync(ctx: &AppContext, project: String, remote: String) -> Result<
         .arg("BatchMode=yes")
         .arg(url)
         .arg(format!(
-            "denali --root {} remote manifest {}",
-            path, project
+            "denali --root {} remote manifest {} --object-format {}",
+            path, project, OBJECT_FORMAT
         ))
         .stdin(Stdio::inherit())
         .stdout(Stdio::piped())
         .spawn()?;
 
-    let mut compressed = Vec::new();
-
-    if let Some(stdout) = ssh.stdout.as_mut() {
-        stdout.read_to_end(&mut compressed)?;
-    } else {
-        return Err(Errors::NoStdout);
-    }
-
-    let mut content = Vec::new();
-    {
-        let mut decoder = Decoder::new(&compressed[..])?;
-        decoder.read_to_end(&mut content)?;
-    }
-
+    let stdout = ssh.stdout.take().ok_or(Errors::NoStdout)?;
+    let level = ctx.compression()?.stream_level();
     let mut pack_stage_one: Vec<u8> = Vec::new();
     let mut pack_stage_two: Vec<u8> = Vec::new();
 
-    unpack_stage_one(
+    let snapshots_to_send = unpack_stage_one(
         ctx,
-        &content,
+        &mut Decoder::new(stdout)?,
         &mut pack_stage_one,
         &mut pack_stage_two,
         project,
     )?;
 
-    let mut compressed_stage_one = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed_stage_one, 3)?;
-        encoder.write_all(&pack_stage_one)?;
-        encoder.finish()?;
-    }
-
     let mut ssh = Command::new("ssh")
         .arg("-o")
         .arg("BatchMode=yes")
         .arg(url)
-        .arg(format!("denali --root {} remote send", path))
+        .arg(format!(
+            "denali --root {} remote send --object-format {}",
+            path, OBJECT_FORMAT
+        ))
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()?;
 
-    let mut stdin = ssh.stdin.take().ok_or(Errors::StdinFailed)?;
-    stdin.write_all(&compressed_stage_one)?;
-    drop(stdin);
+    let stdin = ssh.stdin.take().ok_or(Errors::StdinFailed)?;
+    let mut encoder = Encoder::new(stdin, level)?;
+    encoder.write_all(&pack_stage_one)?;
+    drop(encoder.finish()?);
 
-    let mut compressed = Vec::new();
-
-    if let Some(stdout) = ssh.stdout.as_mut() {
-        stdout.read_to_end(&mut compressed)?;
-    } else {
-        return Err(Errors::NoStdout);
-    }
-
-    let mut content = Vec::new();
-    {
-        let mut decoder = Decoder::new(&compressed[..])?;
-        decoder.read_to_end(&mut content)?;
-    }
-
-    unpack_stage_two(ctx, &content)?;
-
-    let mut compressed_stage_two = Vec::new();
-    {
-        let mut encoder = Encoder::new(&mut compressed_stage_two, 3)?;
-        encoder.write_all(&pack_stage_two)?;
-        encoder.finish()?;
-    }
+    let stdout = ssh.stdout.take().ok_or(Errors::NoStdout)?;
+    unpack_stage_two(ctx, &mut Decoder::new(stdout)?)?;
 
     let mut ssh = Command::new("ssh")
         .arg("-o")
         .arg("BatchMode=yes")
         .arg(url)
-        .arg(format!("denali --root {} remote receive", path))
+        .arg(format!(
+            "denali --root {} remote receive --object-format {}",
+            path, OBJECT_FORMAT
+        ))
         .stdin(Stdio::piped())
         .stdout(Stdio::inherit())
         .spawn()?;
 
-    let mut stdin = ssh.stdin.take().ok_or(Errors::StdinFailed)?;
-    stdin.write_all(&compressed_stage_two)?;
-    drop(stdin);
+    let stdin = ssh.stdin.take().ok_or(Errors::StdinFailed)?;
+    let mut encoder = Encoder::new(stdin, level)?;
+    encoder.write_all(&pack_stage_two)?;
+    pack_snapshots(ctx, snapshots_to_send, &mut encoder)?;
+    drop(encoder.finish()?);
+    // The remote store stays locked until receive has written everything.
+    ssh.wait()?;
 
     Ok(())
 }
 
-fn unpack_stage_two(ctx: &AppContext, content: &[u8]) -> Result<(), Errors> {
-    let mut pointer: u64 = 0;
-    while (pointer as usize) < content.len() {
-        let mode = PackType::from_byte(content[pointer as usize]);
-        pointer += 1;
-        if let Some(mode) = mode {
-            match mode {
-                PackType::Object => unpack_object(ctx, content, &mut pointer)?,
-                PackType::Snapshot => unpack_snapshot(ctx, content, &mut pointer)?,
-                _ => {
-                    break;
-                }
+fn unpack_stage_two(ctx: &AppContext, reader: &mut impl Read) -> Result<(), Errors> {
+    while let Some(mode) = read_type(reader)? {
+        match mode {
+            PackType::Object => unpack_object(ctx, reader)?,
+            PackType::Snapshot => unpack_snapshot(ctx, reader)?,
+            _ => {
+                break;
             }
-        } else {
-            break;
         }
     }
 
@@ -160,13 +125,12 @@ fn unpack_stage_two(ctx: &AppContext, content: &[u8]) -> Result<(), Errors> {
 
 fn unpack_stage_one(
     ctx: &AppContext,
-    output: &[u8],
+    reader: &mut impl Read,
     pack: &mut Vec<u8>,
     send: &mut Vec<u8>,
     project: String,
-) -> Result<(), Errors> {
-    let mut pointer: u64 = 0;
-    let (deleted_projects, mut main_manifest) = diff_manifest(ctx, output, &mut pointer)?;
+) -> Result<Vec<u8>, Errors> {
+    let (deleted_projects, mut main_manifest) = diff_manifest(ctx, reader)?;
     let uuid_to_name: HashMap<String, String> = main_manifest
         .projects
         .iter()
@@ -180,64 +144,59 @@ fn unpack_stage_one(
         temp
     };
     let mut snapshots_to_send = Vec::new();
-    while (pointer as usize) < output.len() {
-        let mode = PackType::from_byte(output[pointer as usize]);
-        pointer += 1;
-        if let Some(mode) = mode {
-            match mode {
-                PackType::Project => {
-                    let (uuid, manifest) = unpack_project(output, &mut pointer)?;
-                    let uuid_str = uuid.to_string();
-
-                    if !deleted_projects.contains(&uuid) {
-                        if let Some(current_name) = uuid_to_name.get(&uuid_str) {
-                            if let Some(proj_ref) = main_manifest.projects.get_mut(current_name) {
-                                let local_proj_manifest =
-                                    ctx.load_project_manifest(uuid_str.clone())?;
-                                let (request, snapshots_send, manifest) =
-                                    diff_project(&local_proj_manifest, &manifest)?;
-                                ctx.write_project_manifest(uuid_str.clone(), &manifest)?;
-                                pack.extend_from_slice(&request);
-                                snapshots_to_send.extend_from_slice(&snapshots_send);
-
-                                if let Some(latest) = newest_snapshot(&manifest.snapshots) {
-                                    proj_ref.latest = latest.hash;
-                                } else {
-                                    proj_ref.latest = String::new();
-                                }
-                                proj_ref.cells =
-                                    manifest
-                                        .cells
-                                        .iter()
-                                        .filter_map(|(n, c)| {
-                                            if !c.is_deleted { Some(n.clone()) } else { None }
-                                        })
-                                        .collect();
-
-                                let bytes = serde_json::to_vec(&manifest)?;
-                                let size = bytes.len() as u64;
-                                let mode = PackType::Project.as_byte();
-                                send.push(mode);
-                                send.extend_from_slice(uuid.as_bytes());
-                                send.extend_from_slice(&size.to_be_bytes());
-                                send.extend_from_slice(&bytes);
-                                remote_new_projects.remove(&uuid_str);
+    while let Some(mode) = read_type(reader)? {
+        match mode {
+            PackType::Project => {
+                let (uuid, manifest) = unpack_project(reader)?;
+                let uuid_str = uuid.to_string();
+
+                if !deleted_projects.contains(&uuid) {
+                    if let Some(current_name) = uuid_to_name.get(&uuid_str) {
+                        if let Some(proj_ref) = main_manifest.projects.get_mut(current_name) {
+                            let local_proj_manifest =
+                                ctx.load_project_manifest(uuid_str.clone())?;
+                            let (request, snapshots_send, manifest) =
+                                diff_project(&local_proj_manifest, &manifest)?;
+                            ctx.write_project_manifest(uuid_str.clone(), &manifest)?;
+                            pack.extend_from_slice(&request);
+                            snapshots_to_send.extend_from_slice(&snapshots_send);
+
+                            if let Some(latest) = newest_snapshot(&manifest.snapshots) {
+                                proj_ref.latest = latest.hash;
                             } else {
-                                eprintln!("UUIDs do not match");
-                                return Err(Errors::InternalError);
+                                proj_ref.latest = String::new();
                             }
+                            proj_ref.cells = manifest
+                                .cells
+                                .iter()
+                                .filter_map(
+                                    |(n, c)| {
+                                        if !c.is_deleted { Some(n.clone()) } else { None }
+                                    },
+                                )
+                                .collect();
+
+                            let bytes = serde_json::to_vec(&manifest)?;
+                            let size = bytes.len() as u64;
+                            let mode = PackType::Project.as_byte();
+                            send.push(mode);
+                            send.extend_from_slice(uuid.as_bytes());
+                            send.extend_from_slice(&size.to_be_bytes());
+                            send.extend_from_slice(&bytes);
+                            remote_new_projects.remove(&uuid_str);
                         } else {
-                            eprintln!("No such UUID: {}", uuid.to_string());
+                            eprintln!("UUIDs do not match");
                             return Err(Errors::InternalError);
                         }
+                    } else {
+                        eprintln!("No such UUID: {}", uuid.to_string());
+                        return Err(Errors::InternalError);
                     }
                 }
-                _ => {
-                    break;
-                }
             }
-        } else {
-            break;
+            _ => {
+                break;
+            }
         }
     }
 
@@ -267,27 +226,19 @@ fn unpack_stage_one(
     send.extend_from_slice(&bytes);
     ctx.write_main_manifest(&main_manifest)?;
 
-    pack_snapshots(ctx, snapshots_to_send, send)?;
-    Ok(())
+    Ok(snapshots_to_send)
 }
 
 fn diff_manifest(
     ctx: &AppContext,
-    output: &[u8],
-    pointer: &mut u64,
+    reader: &mut impl Read,
 ) -> Result<(HashSet<Uuid>, MainManifest), Errors> {
     let mut manifest = ctx.load_main_manifest()?;
     let mut deleted_uuids = HashSet::new();
-    let mut i = *pointer as usize;
-    let mode = PackType::from_byte(output[i]);
-    i += 1;
-    if let Some(mode) = mode {
+    if let Some(mode) = read_type(reader)? {
         if mode.as_byte() == PackType::Main.as_byte() {
-            let size = u64::from_be_bytes(output[i..i + 8].try_into()?);
-            i += 8;
             let incoming_projects: HashMap<String, ProjectRef> =
-                serde_json::from_slice(&output[i..i + size as usize])?;
-            i += size as usize;
+                serde_json::from_slice(&read_record(reader)?)?;
 
             let uuid_to_name: HashMap<String, String> = manifest
                 .projects
@@ -345,8 +296,6 @@ fn diff_manifest(
         return Err(Errors::InternalError);
     }
 
-    *pointer = i as u64;
-
     Ok((deleted_uuids, manifest))
 }
 
@@ -379,21 +328,14 @@ fn diff_project(
     Ok((fetch, send, new_manifest))
 }
 
-fn unpack_project(content: &[u8], pointer: &mut u64) -> Result<(Uuid, ProjectManifest), Errors> {
-    let mut i = *pointer as usize;
-
-    let uuid = Uuid::from_bytes(content[i..i + 16].try_into()?);
-    i += 16;
+fn unpack_project(reader: &mut impl Read) -> Result<(Uuid, ProjectManifest), Errors> {
+    let mut uuid = [0u8; 16];
+    reader.read_exact(&mut uuid)?;
+    let uuid = Uuid::from_bytes(uuid);
 
-    let size = u64::from_be_bytes(content[i..i + 8].try_into()?);
-    i += 8;
+    let data = read_record(reader)?;
 
-    let data = &content[i..i + size as usize];
-    i += size as usize;
-
-    *pointer = i as u64;
-
-    let manifest: ProjectManifest = serde_json::from_slice(data)?;
+    let manifest: ProjectManifest = serde_json::from_slice(&data)?;
 
     Ok((uuid, manifest))
 }
@@ -596,7 +538,11 @@ fn newest_snapshot(snapshots: &HashMap<String, Snapshots>) -> Option<Snapshots>
     snap_meta
 }
 
-fn pack_snapshots(ctx: &AppContext, snapshots: Vec<u8>, send: &mut Vec<u8>) -> Result<(), Errors> {
+fn pack_snapshots(
+    ctx: &AppContext,
+    snapshots: Vec<u8>,
+    send: &mut impl Write,
+) -> Result<(), Errors> {
     let mut i = 0;
     let mut copied = HashSet::new();
     while i < snapshots.len() {
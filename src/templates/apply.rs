@@ -1,9 +1,10 @@
-use crate::utils::TreeStruct;
 use crate::utils::file_type::FileType;
+use crate::utils::tree_codec::parse_tree;
 use crate::utils::{Errors, TemplateRef, TmplToml, context::AppContext};
 use dialoguer::Input;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::{collections::HashMap, env, fs, path::Path};
 
@@ -102,38 +103,6 @@ fn restore_file(ctx: &AppContext, hash: String, dest: &Path, mode: [u8; 4]) -> R
     Ok(())
 }
 
-fn parse_tree(tree: &Vec<u8>) -> Result<Vec<TreeStruct>, Errors> {
-    let mut entries = Vec::new();
-
-    let mut i = 0;
-    while i < tree.len() {
-        let mode_start = i;
-        while tree[i] != b' ' {
-            i += 1;
-        }
-        let mode: [u8; 4] = tree[mode_start..i].try_into()?;
-        i += 1;
-
-        let name_start = i;
-        while tree[i] != 0 {
-            i += 1;
-        }
-        let name = String::from_utf8_lossy(&tree[name_start..i]).to_string();
-        i += 1;
-
-        let hash: [u8; 32] = tree[i..i + 32].try_into()?;
-        i += 32;
-
-        entries.push(TreeStruct {
-            mode,
-            name: name,
-            hash,
-        });
-    }
-
-    Ok(entries)
-}
-
 fn restore(ctx: &AppContext, hash: String, dest: &Path) -> Result<(), Errors> {
     let tree = ctx.load_object(hash)?;
 
@@ -165,9 +134,9 @@ fn restore(ctx: &AppContext, hash: String, dest: &Path) -> Result<(), Errors> {
                     }
                 }
                 let stored = ctx.load_object(hex::encode(entry.hash))?;
-                let symlink_target = PathBuf::from(String::from_utf8_lossy(&stored).to_string());
+                let symlink_target = Path::new(OsStr::from_bytes(&stored));
 
-                std::os::unix::fs::symlink(&symlink_target, &target)?;
+                std::os::unix::fs::symlink(symlink_target, &target)?;
             }
             FileType::Regular => {
                 restore_file(ctx, hex::encode(entry.hash), &target, entry.mode)?;
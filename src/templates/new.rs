@@ -2,11 +2,14 @@ use std::{
     env,
     fs::{self, File},
     io::Read,
-    os::unix::fs::MetadataExt,
+    os::unix::{ffi::OsStringExt, fs::MetadataExt},
     path::Path,
 };
 
-use crate::utils::{Errors, TemplateRef, TreeStruct, context::AppContext};
+use crate::utils::{
+    Errors, TemplateRef, TreeStruct, atomic::write_atomic, context::AppContext,
+    tree_codec::encode_tree,
+};
 
 pub fn tmpl_new(
     ctx: &AppContext,
@@ -41,7 +44,7 @@ pub fn tmpl_new(
 
     let hash = snapshot_dir(ctx, &dir)?;
     let config_path = ctx.templates_path().join(format!("{}.toml", name));
-    fs::write(&config_path, template_data)?;
+    write_atomic(&config_path, &template_data)?;
 
     manifest.templates.insert(
         name,
@@ -54,21 +57,6 @@ pub fn tmpl_new(
     Ok(())
 }
 
-fn build_tree(ctx: &AppContext, entries: Vec<TreeStruct>) -> Result<[u8; 32], Errors> {
-    let mut content = Vec::new();
-
-    for entry in entries {
-        content.extend_from_slice(&entry.mode);
-        content.push(b' ');
-        content.extend_from_slice(entry.name.as_bytes());
-        content.push(0);
-        content.extend_from_slice(&entry.hash);
-    }
-
-    let hash = ctx.save_object(content)?;
-    Ok(hash)
-}
-
 fn hash_file(ctx: &AppContext, path: &Path) -> Result<[u8; 32], Errors> {
     let mut file = File::open(path)?;
     let mut content = Vec::new();
@@ -93,7 +81,7 @@ fn snapshot_dir(ctx: &AppContext, path: &Path) -> Result<[u8; 32], Errors> {
 
             let hash = if meta.file_type().is_symlink() {
                 let target = fs::read_link(&path)?;
-                ctx.save_object(target.to_string_lossy().as_bytes().to_vec())?
+                ctx.save_object(target.into_os_string().into_vec())?
             } else if meta.is_dir() {
                 snapshot_dir(ctx, &path)?
             } else {
@@ -103,8 +91,9 @@ fn snapshot_dir(ctx: &AppContext, path: &Path) -> Result<[u8; 32], Errors> {
             if name_os != ".denali.tmpl.toml" {
                 entries.push(TreeStruct {
                     mode,
-                    name: name_os.to_string_lossy().to_string(),
+                    name: name_os.to_os_string(),
                     hash,
+                    meta: None,
                 });
             }
         }
@@ -118,12 +107,13 @@ fn snapshot_dir(ctx: &AppContext, path: &Path) -> Result<[u8; 32], Errors> {
             let mode = meta.mode().to_be_bytes();
             entries.push(TreeStruct {
                 mode,
-                name: name_os.to_string_lossy().to_string(),
+                name: name_os.to_os_string(),
                 hash,
+                meta: None,
             });
         }
     }
 
-    let hash = build_tree(ctx, entries)?;
+    let hash = ctx.save_object(encode_tree(&entries)?)?;
     Ok(hash)
 }
@@ -0,0 +1,65 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use super::Errors;
+
+/// Replaces `path` with `data` so that a crash leaves either the old or the new
+/// content behind, never a truncated file.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Errors> {
+    write_atomic_with(path, |file| Ok(file.write_all(data)?))
+}
+
+/// Like `write_atomic`, for content that `write` streams into the file.
+pub fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<(), Errors>,
+) -> Result<(), Errors> {
+    let tmp = sibling_tmp(path);
+    let written = File::create(&tmp)
+        .map_err(Errors::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            Ok(file.sync_all()?)
+        });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    rename_synced(&tmp, path)
+}
+
+/// Writes and fsyncs a new file.
+pub fn write_synced(path: &Path, data: &[u8]) -> Result<(), Errors> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Moves a synced file into place and fsyncs the directory holding it, so the
+/// rename itself survives a power loss.
+pub fn rename_synced(from: &Path, to: &Path) -> Result<(), Errors> {
+    fs::rename(from, to)?;
+    if let Some(dir) = to.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+pub fn sync_dir(dir: &Path) -> Result<(), Errors> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn sibling_tmp(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, Uuid::new_v4()))
+}
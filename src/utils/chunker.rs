@@ -0,0 +1,251 @@
+use std::io::{self, Read, Write};
+
+use super::{Errors, context::AppContext, file_type::FileType};
+
+/// Files at least this large are split into chunks instead of stored as one blob.
+pub const CHUNK_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// Mode bits marking a regular file stored as a chunk list.
+pub const CHUNKED_MODE: u32 = 0x9000;
+
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// Top 20 bits of the gear hash, for an average chunk of about 1 MiB.
+const CUT_MASK: u64 = !0 << 44;
+const LIST_RECORD: usize = 40;
+
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Content-defined chunker using a gear rolling hash. Only one chunk is kept in
+/// memory at a time.
+pub struct Chunker<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        if self.start > 0 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+
+        while !self.eof && self.buffer.len() < MAX_CHUNK {
+            let len = self.buffer.len();
+            self.buffer.resize(MAX_CHUNK, 0);
+            let read = self.reader.read(&mut self.buffer[len..])?;
+            self.buffer.truncate(len + read);
+            if read == 0 {
+                self.eof = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.fill()?;
+
+        let data = &self.buffer[self.start..];
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let mut cut = data.len();
+        let mut hash: u64 = 0;
+        for (i, byte) in data.iter().enumerate().skip(MIN_CHUNK) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & CUT_MASK == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+
+        let chunk = data[..cut].to_vec();
+        self.start += cut;
+        Ok(Some(chunk))
+    }
+}
+
+pub fn push_chunk(list: &mut Vec<u8>, hash: &[u8; 32], size: u64) {
+    list.extend_from_slice(hash);
+    list.extend_from_slice(&size.to_be_bytes());
+}
+
+/// Hash and size of every chunk in a chunk list object.
+pub fn parse_chunk_list(list: &[u8]) -> Result<Vec<([u8; 32], u64)>, Errors> {
+    if !list.len().is_multiple_of(LIST_RECORD) {
+        return Err(Errors::InternalError);
+    }
+
+    let mut chunks = Vec::with_capacity(list.len() / LIST_RECORD);
+    for record in list.chunks_exact(LIST_RECORD) {
+        let hash: [u8; 32] = record[..32].try_into()?;
+        let size = u64::from_be_bytes(record[32..].try_into()?);
+        chunks.push((hash, size));
+    }
+
+    Ok(chunks)
+}
+
+/// Writes the content of a file entry, joining the chunks of chunked files.
+pub fn write_content(
+    ctx: &AppContext,
+    hash: &str,
+    mode: u32,
+    out: &mut impl Write,
+) -> Result<(), Errors> {
+    if FileType::from_mode(mode) != FileType::Chunked {
+        io::copy(&mut ctx.open_object(hash)?, out)?;
+        return Ok(());
+    }
+
+    for (chunk, _) in parse_chunk_list(&ctx.load_object(hash.to_string())?)? {
+        io::copy(&mut ctx.open_object(&hex::encode(chunk))?, out)?;
+    }
+
+    Ok(())
+}
+
+pub fn load_content(ctx: &AppContext, hash: &str, mode: u32) -> Result<Vec<u8>, Errors> {
+    let mut content = Vec::new();
+    write_content(ctx, hash, mode, &mut content)?;
+    Ok(content)
+}
+
+/// First `limit` bytes of a file entry, reading only as much of it as needed.
+pub fn content_head(
+    ctx: &AppContext,
+    hash: &str,
+    mode: u32,
+    limit: usize,
+) -> Result<Vec<u8>, Errors> {
+    let mut head = Vec::new();
+    if FileType::from_mode(mode) != FileType::Chunked {
+        ctx.open_object(hash)?
+            .take(limit as u64)
+            .read_to_end(&mut head)?;
+        return Ok(head);
+    }
+
+    for (chunk, _) in parse_chunk_list(&ctx.load_object(hash.to_string())?)? {
+        let missing = (limit - head.len()) as u64;
+        ctx.open_object(&hex::encode(chunk))?
+            .take(missing)
+            .read_to_end(&mut head)?;
+        if head.len() >= limit {
+            break;
+        }
+    }
+
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic incompressible bytes.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunks_cover_the_input_within_bounds() {
+        let data = noise(12 * 1024 * 1024, 1);
+        let chunks = chunks(&data);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= MIN_CHUNK && chunk.len() <= MAX_CHUNK);
+        }
+    }
+
+    #[test]
+    fn boundaries_stay_put_after_an_insertion() {
+        let data = noise(16 * 1024 * 1024, 2);
+        let mut edited = data.clone();
+        let at = 5 * 1024 * 1024 + 123;
+        edited.splice(at..at, b"inserted bytes".iter().copied());
+
+        let before = chunks(&data);
+        let after = chunks(&edited);
+        let shared = after.iter().filter(|chunk| before.contains(chunk)).count();
+
+        assert_eq!(before[0], after[0]);
+        assert_eq!(before.last(), after.last());
+        assert!(
+            shared + 2 >= before.len(),
+            "{shared} of {} chunks kept",
+            before.len()
+        );
+    }
+
+    #[test]
+    fn chunk_list_round_trips() {
+        let mut list = Vec::new();
+        push_chunk(&mut list, &[1; 32], 10);
+        push_chunk(&mut list, &[2; 32], u64::MAX);
+
+        assert_eq!(
+            parse_chunk_list(&list).unwrap(),
+            vec![([1; 32], 10), ([2; 32], u64::MAX)]
+        );
+        assert!(parse_chunk_list(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_chunk_list_is_rejected() {
+        let mut list = Vec::new();
+        push_chunk(&mut list, &[1; 32], 10);
+        push_chunk(&mut list, &[2; 32], 20);
+
+        assert!(parse_chunk_list(&list[..list.len() - 1]).is_err());
+        assert!(parse_chunk_list(&list[..LIST_RECORD + 32]).is_err());
+        assert!(parse_chunk_list(&[0; 7]).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    fmt,
+    io::{self, BufReader, Read, Write},
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use zstd::{Decoder, Encoder, dict::EncoderDictionary, zstd_safe};
+
+use super::Errors;
+
+/// Starts every object stored without compression. Compressed objects start with
+/// the zstd frame magic instead.
+const RAW_MAGIC: &[u8; 4] = b"DNLR";
+
+/// Bytes of content looked at to recognise already compressed formats.
+pub const HEAD_LEN: usize = 16;
+
+/// Longest zstd frame header, which holds the dictionary id.
+const FRAME_HEADER_MAX: usize = 18;
+
+/// Ids zstd leaves free for private dictionaries, the range it trains into.
+pub const MIN_DICT_ID: u32 = 32768;
+pub const MAX_DICT_ID: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Zstd,
+    None,
+}
+
+/// How objects are stored, written as `zstd`, `zstd:<level>` or `none`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    pub level: i32,
+    /// Trained dictionary of the project being saved, never written out.
+    pub dictionary: Option<u32>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Zstd,
+            level: 3,
+            dictionary: None,
+        }
+    }
+}
+
+impl Compression {
+    pub const NONE: Self = Self {
+        algorithm: Algorithm::None,
+        level: 0,
+        dictionary: None,
+    };
+
+    pub fn with_dictionary(self, dictionary: Option<u32>) -> Self {
+        Self { dictionary, ..self }
+    }
+
+    /// Content that already starts like a compressed format is stored as it is.
+    pub fn for_content(self, head: &[u8]) -> Self {
+        if is_compressed(head) {
+            Self::NONE
+        } else {
+            self
+        }
+    }
+
+    /// Level for streams that are always zstd, such as sync. Uncompressed stores
+    /// use the fastest one.
+    pub fn stream_level(self) -> i32 {
+        match self.algorithm {
+            Algorithm::Zstd => self.level,
+            Algorithm::None => 1,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (algorithm, level) = match value.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (value, None),
+        };
+
+        match (algorithm, level) {
+            ("none", None) => Ok(Self::NONE),
+            ("zstd", None) => Ok(Self::default()),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => Ok(Self {
+                    level,
+                    ..Self::default()
+                }),
+                _ => Err(format!("Invalid zstd level \"{}\"", level)),
+            },
+            _ => Err(format!(
+                "Invalid compression \"{}\", expected `zstd`, `zstd:<level>` or `none`",
+                value
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.algorithm {
+            Algorithm::Zstd => write!(f, "zstd:{}", self.level),
+            Algorithm::None => write!(f, "none"),
+        }
+    }
+}
+
+impl From<Compression> for String {
+    fn from(value: Compression) -> Self {
+        value.to_string()
+    }
+}
+
+/// Magic bytes of common formats that do not get smaller when compressed again.
+fn is_compressed(head: &[u8]) -> bool {
+    const MAGICS: &[&[u8]] = &[
+        b"\xFF\xD8\xFF",       // jpeg
+        b"\x89PNG\r\n\x1A\n",  // png
+        b"GIF8",               // gif
+        b"PK\x03\x04",         // zip, jar, docx, apk
+        b"\x1F\x8B",           // gzip
+        b"\x28\xB5\x2F\xFD",   // zstd
+        b"\xFD7zXZ\x00",       // xz
+        b"BZh",                // bzip2
+        b"7z\xBC\xAF\x27\x1C", // 7z
+        b"Rar!\x1A\x07",       // rar
+        b"\x04\x22\x4D\x18",   // lz4
+        b"OggS",               // ogg
+        b"fLaC",               // flac
+        b"ID3",                // mp3
+        b"\x1A\x45\xDF\xA3",   // matroska, webm
+    ];
+
+    MAGICS.iter().any(|magic| head.starts_with(magic))
+        || (head.len() >= 12 && &head[4..8] == b"ftyp") // mp4, mov, heic
+        || (head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP"))
+}
+
+pub fn compress(
+    content: &[u8],
+    compression: Compression,
+    dictionary: Option<&EncoderDictionary>,
+) -> Result<Vec<u8>, Errors> {
+    let mut writer = CompressWriter::new(Vec::new(), compression, dictionary)?;
+    writer.write_all(content)?;
+    Ok(writer.finish()?)
+}
+
+/// Reader over the content of a stored object, compressed or not. `dictionary`
+/// is asked for the dictionary the zstd frame header names, if any.
+pub fn decompress<'a>(
+    mut stored: impl Read + 'a,
+    dictionary: impl FnOnce(u32) -> Result<Arc<Vec<u8>>, Errors>,
+) -> Result<Box<dyn Read + 'a>, Errors> {
+    let mut head = Vec::with_capacity(FRAME_HEADER_MAX);
+    (&mut stored)
+        .take(FRAME_HEADER_MAX as u64)
+        .read_to_end(&mut head)?;
+
+    if head.starts_with(RAW_MAGIC) {
+        return Ok(Box::new(
+            io::Cursor::new(head.split_off(RAW_MAGIC.len())).chain(stored),
+        ));
+    }
+
+    let id = zstd_safe::get_dict_id_from_frame(&head);
+    let reader = io::Cursor::new(head).chain(stored);
+    match id {
+        Some(id) => Ok(Box::new(Decoder::with_dictionary(
+            BufReader::new(reader),
+            &dictionary(id.get())?,
+        )?)),
+        None => Ok(Box::new(Decoder::new(reader)?)),
+    }
+}
+
+/// Writes objects in the stored form of `compression`. `dictionary` has to be
+/// the prepared one `compression` names.
+pub enum CompressWriter<'a, W: Write> {
+    Zstd(Encoder<'a, W>),
+    Raw(W),
+}
+
+impl<'a, W: Write> CompressWriter<'a, W> {
+    pub fn new(
+        mut inner: W,
+        compression: Compression,
+        dictionary: Option<&'a EncoderDictionary<'a>>,
+    ) -> io::Result<Self> {
+        match compression.algorithm {
+            Algorithm::Zstd => Ok(Self::Zstd(match dictionary {
+                Some(dictionary) => Encoder::with_prepared_dictionary(inner, dictionary)?,
+                None => Encoder::new(inner, compression.level)?,
+            })),
+            Algorithm::None => {
+                inner.write_all(RAW_MAGIC)?;
+                Ok(Self::Raw(inner))
+            }
+        }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Raw(inner) => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Raw(inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Raw(inner) => inner.flush(),
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ffi::OsString};
 
 use serde::{Deserialize, Serialize};
 
+use super::{compression::Compression, entry_meta::EntryMeta};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProjectConfig {
     pub name: String,
@@ -15,6 +17,12 @@ pub struct ProjectConfig {
     pub snapshot_after: String,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub remote: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,6 +38,31 @@ pub struct CellConfig {
     pub snapshot_before: String,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub snapshot_after: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_last: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_hourly: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_daily: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_weekly: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_monthly: usize,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,6 +73,26 @@ pub struct DenaliToml {
     pub cells: HashMap<String, CellConfig>,
 }
 
+impl DenaliToml {
+    /// Compression of a cell, or of the project files outside cells with `None`.
+    /// Cells without their own setting use the one of `root`, then the store's.
+    pub fn compression(&self, cell: Option<&str>, store: Compression) -> Compression {
+        cell.and_then(|cell| self.cells.get(cell))
+            .and_then(|cell| cell.compression)
+            .or(self.root.compression)
+            .unwrap_or(store)
+    }
+
+    /// Whether changed files of a cell, or of the project files outside cells with
+    /// `None`, are stored as deltas. Falls back to `root` like `compression`.
+    pub fn delta(&self, cell: Option<&str>) -> bool {
+        cell.and_then(|cell| self.cells.get(cell))
+            .and_then(|cell| cell.delta)
+            .or(self.root.delta)
+            .unwrap_or(false)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TmplToml {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -50,6 +103,7 @@ pub struct TmplToml {
 
 pub struct TreeStruct {
     pub mode: [u8; 4],
-    pub name: String,
+    pub name: OsString,
     pub hash: [u8; 32],
+    pub meta: Option<EntryMeta>,
 }
@@ -1,17 +1,44 @@
 use std::{
     collections::HashMap,
-    env, fs,
-    io::{Read, Write},
-    path::PathBuf,
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
 };
 
 use dirs::home_dir;
-use zstd::{Decoder, Encoder};
+use uuid::Uuid;
+use zstd::{Encoder, dict::EncoderDictionary};
+
+use super::{
+    Errors, MainManifest, ProjectManifest, SNAPSHOT_FORMAT, Snapshot,
+    atomic::{rename_synced, sync_dir, write_atomic, write_synced},
+    compression::{Algorithm, CompressWriter, Compression, HEAD_LEN, compress, decompress},
+    delta::{self, DeltaHeader},
+    lock::{LockMode, StoreLock},
+    pack::{PackIndex, read_packs},
+    store_config::StoreConfig,
+};
 
-use super::{Errors, MainManifest, ProjectManifest, Snapshot};
+/// How objects and snapshots are named. Format 1 hashed the compressed bytes,
+/// format 2 hashes the content, so names do not depend on the zstd build.
+pub const OBJECT_FORMAT: u32 = 2;
 
 pub struct AppContext {
     pub root: PathBuf,
+    /// How long `lock` keeps retrying a busy store, `None` fails right away.
+    pub lock_wait: Option<Duration>,
+    /// Worker threads for hashing and restoring files.
+    pub jobs: usize,
+    object_packs: OnceLock<Vec<PackIndex>>,
+    snapshot_packs: OnceLock<Vec<PackIndex>>,
+    config: OnceLock<StoreConfig>,
+    dictionaries: Mutex<HashMap<u32, Arc<Vec<u8>>>>,
+    /// Dictionaries prepared for compression, by id and level.
+    prepared: Mutex<HashMap<(u32, i32), Arc<EncoderDictionary<'static>>>>,
 }
 
 impl AppContext {
@@ -33,7 +60,16 @@ impl AppContext {
 
         let root = root.canonicalize()?;
 
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            lock_wait: None,
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            object_packs: OnceLock::new(),
+            snapshot_packs: OnceLock::new(),
+            config: OnceLock::new(),
+            dictionaries: Mutex::new(HashMap::new()),
+            prepared: Mutex::new(HashMap::new()),
+        })
     }
 
     pub fn main_manifest_path(&self) -> PathBuf {
@@ -56,6 +92,43 @@ impl AppContext {
         self.root.join("objects")
     }
 
+    pub fn tmp_path(&self) -> PathBuf {
+        self.root.join("tmp")
+    }
+
+    pub fn object_packs_path(&self) -> PathBuf {
+        self.objects_path().join("pack")
+    }
+
+    pub fn snapshot_packs_path(&self) -> PathBuf {
+        self.snapshots_path().join("pack")
+    }
+
+    pub fn stat_caches_path(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    pub fn stat_cache_path(&self, uuid: &str) -> PathBuf {
+        self.stat_caches_path().join(format!("{}.json", uuid))
+    }
+
+    pub fn journal_path(&self) -> PathBuf {
+        self.root.join("journal")
+    }
+
+    pub fn store_config_path(&self) -> PathBuf {
+        self.root.join("config")
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.root.join("lock")
+    }
+
+    /// Shared lock for commands that only read the store, exclusive for writers.
+    pub fn lock(&self, mode: LockMode) -> Result<StoreLock, Errors> {
+        StoreLock::acquire(&self.lock_path(), mode, self.lock_wait)
+    }
+
     pub fn make_root_dir(&self) -> Result<(), Errors> {
         let path = self.root.clone();
 
@@ -69,13 +142,16 @@ impl AppContext {
         fs::create_dir_all(path.join("templates"))?;
         let manifest_file = path.join("manifest.json");
         if !manifest_file.exists() {
+            if !self.store_config_path().exists() {
+                StoreConfig::current().write(self)?;
+            }
             let manifest_obj: MainManifest = MainManifest {
                 projects: HashMap::new(),
                 templates: HashMap::new(),
                 remotes: HashMap::new(),
             };
             let manifest_data = serde_json::to_vec_pretty(&manifest_obj)?;
-            fs::write(manifest_file, manifest_data)?;
+            write_atomic(&manifest_file, &manifest_data)?;
         };
         Ok(())
     }
@@ -94,7 +170,7 @@ impl AppContext {
 
     pub fn write_main_manifest(&self, manifest: &MainManifest) -> Result<(), Errors> {
         let data = serde_json::to_vec_pretty(manifest)?;
-        fs::write(self.main_manifest_path(), &data)?;
+        write_atomic(&self.main_manifest_path(), &data)?;
         Ok(())
     }
 
@@ -104,79 +180,468 @@ impl AppContext {
         manifest: &ProjectManifest,
     ) -> Result<(), Errors> {
         let data = serde_json::to_vec_pretty(manifest)?;
-        fs::write(self.project_manifest_path(uuid), &data)?;
+        write_atomic(&self.project_manifest_path(uuid), &data)?;
         Ok(())
     }
 
     pub fn save_object(&self, content: Vec<u8>) -> Result<[u8; 32], Errors> {
-        let mut compressed = Vec::new();
-        {
-            let mut encoder = Encoder::new(&mut compressed, 3)?;
-            encoder.write_all(&content)?;
-            encoder.finish()?;
+        self.save_object_as(content, self.compression()?)
+    }
+
+    pub fn save_object_as(
+        &self,
+        content: Vec<u8>,
+        compression: Compression,
+    ) -> Result<[u8; 32], Errors> {
+        self.store_bytes(Store::Objects, &content, compression)
+    }
+
+    /// Compresses and stores everything `reader` yields, hashing it on the way.
+    pub fn save_object_from(
+        &self,
+        reader: &mut impl Read,
+        compression: Compression,
+    ) -> Result<[u8; 32], Errors> {
+        self.store_from(Store::Objects, reader, compression)
+    }
+
+    pub fn hash_object(&self, content: &[u8]) -> Result<[u8; 32], Errors> {
+        self.hash_object_from(&mut &content[..])
+    }
+
+    /// Name the content would get in this store, without storing it.
+    pub fn hash_object_from(&self, reader: &mut impl Read) -> Result<[u8; 32], Errors> {
+        if self.object_format()? == 1 {
+            let mut encoder = Encoder::new(HashWriter::new(io::sink()), 3)?;
+            io::copy(reader, &mut encoder)?;
+            return Ok(encoder.finish()?.finalize());
         }
 
-        let hash = blake3::hash(&compressed);
-        let name = hash.to_hex().to_string();
-        let dir = &name[..3];
-        let filename = &name[3..];
+        let mut hasher = blake3::Hasher::new();
+        io::copy(reader, &mut hasher)?;
+        Ok(*hasher.finalize().as_bytes())
+    }
 
-        let obj_path = self.root.join("objects").join(dir);
-        fs::create_dir_all(&obj_path)?;
-        let file_path = obj_path.join(filename);
+    fn config(&self) -> Result<&StoreConfig, Errors> {
+        if let Some(config) = self.config.get() {
+            return Ok(config);
+        }
+        let config = StoreConfig::load(self)?;
+        Ok(self.config.get_or_init(|| config))
+    }
 
-        fs::write(&file_path, &compressed)?;
+    /// Object format of the store as recorded in its config.
+    pub fn object_format(&self) -> Result<u32, Errors> {
+        Ok(self.config()?.object_format)
+    }
 
-        Ok(*hash.as_bytes())
+    /// Compression of the store, which cells can override.
+    pub fn compression(&self) -> Result<Compression, Errors> {
+        Ok(self.config()?.compression)
     }
 
-    pub fn save_snapshot(&self, content: Vec<u8>) -> Result<[u8; 32], Errors> {
-        let mut compressed = Vec::new();
+    /// Dictionary trained for a project, see `train_dict`.
+    pub fn project_dictionary(&self, uuid: &str) -> Result<Option<u32>, Errors> {
+        Ok(self.config()?.dictionaries.get(uuid).copied())
+    }
+
+    pub fn dictionary_path(&self, id: u32) -> PathBuf {
+        self.root.join("dicts").join(id.to_string())
+    }
+
+    /// Content of a stored dictionary, read once per run.
+    pub fn dictionary(&self, id: u32) -> Result<Arc<Vec<u8>>, Errors> {
+        let mut cache = self
+            .dictionaries
+            .lock()
+            .map_err(|_| Errors::InternalError)?;
+        if let Some(dictionary) = cache.get(&id) {
+            return Ok(dictionary.clone());
+        }
+
+        let dictionary = match fs::read(self.dictionary_path(id)) {
+            Ok(data) => Arc::new(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Errors::DictionaryNotFound(id));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        cache.insert(id, dictionary.clone());
+        Ok(dictionary)
+    }
+
+    /// Dictionary prepared for the level of `compression`, if it names one.
+    fn encoder_dictionary(
+        &self,
+        compression: Compression,
+    ) -> Result<Option<Arc<EncoderDictionary<'static>>>, Errors> {
+        let Some(id) = compression.dictionary else {
+            return Ok(None);
+        };
+        if compression.algorithm != Algorithm::Zstd {
+            return Ok(None);
+        }
+
+        let key = (id, compression.level);
+        if let Some(prepared) = self
+            .prepared
+            .lock()
+            .map_err(|_| Errors::InternalError)?
+            .get(&key)
         {
-            let mut encoder = Encoder::new(&mut compressed, 3)?;
-            encoder.write_all(&content)?;
-            encoder.finish()?;
+            return Ok(Some(prepared.clone()));
         }
 
-        let hash = blake3::hash(&compressed);
-        let name = hash.to_hex().to_string();
-        let dir = &name[..3];
-        let filename = &name[3..];
+        let prepared = Arc::new(EncoderDictionary::copy(
+            &self.dictionary(id)?,
+            compression.level,
+        ));
+        self.prepared
+            .lock()
+            .map_err(|_| Errors::InternalError)?
+            .insert(key, prepared.clone());
+        Ok(Some(prepared))
+    }
 
-        let obj_path = self.root.join("snapshots").join(dir);
-        fs::create_dir_all(&obj_path)?;
-        let file_path = obj_path.join(filename);
+    /// Reader over the content of a stored object or snapshot. Deltas are
+    /// resolved against their base, which is read whole.
+    pub fn decompress<'a>(&self, mut stored: impl Read + 'a) -> Result<Box<dyn Read + 'a>, Errors> {
+        let (header, head) = delta::read_header(&mut stored)?;
+        if let Some(header) = header {
+            let base = self.load_object(hex::encode(header.base))?;
+            return Ok(Box::new(io::Cursor::new(delta::decode(stored, &base)?)));
+        }
+        decompress(io::Cursor::new(head).chain(stored), |id| {
+            self.dictionary(id)
+        })
+    }
 
-        fs::write(&file_path, &compressed)?;
+    /// Header of an object stored as a delta, `None` for whole objects.
+    pub fn delta_header(&self, hash: &str) -> Result<Option<DeltaHeader>, Errors> {
+        let (header, _) = delta::read_header(&mut self.open_stored(Store::Objects, hash)?)?;
+        Ok(header)
+    }
 
-        Ok(*hash.as_bytes())
+    /// Stores `content` as a delta against the object `base` when that is much
+    /// smaller than storing it whole. Once a chain is `MAX_DEPTH` long the next
+    /// version is stored whole again.
+    pub fn save_object_delta(
+        &self,
+        content: Vec<u8>,
+        base: &[u8; 32],
+        compression: Compression,
+    ) -> Result<[u8; 32], Errors> {
+        let hash = *blake3::hash(&content).as_bytes();
+        if self.is_stored(Store::Objects, &hash)? || !self.is_stored(Store::Objects, base)? {
+            return self.save_object_as(content, compression);
+        }
+
+        let base_hash = hex::encode(base);
+        let depth = match self.delta_header(&base_hash)? {
+            Some(header) => header.depth + 1,
+            None => 1,
+        };
+        let whole = self.compress(&content, compression)?;
+        if depth > delta::MAX_DEPTH {
+            return self.write_stored(Store::Objects, &hash, &whole);
+        }
+
+        let header = DeltaHeader { depth, base: *base };
+        let stored = match delta::encode(
+            &content,
+            &self.load_object(base_hash)?,
+            &header,
+            compression.stream_level(),
+        ) {
+            Ok(stored) if stored.len() * delta::MAX_RATIO <= whole.len() => stored,
+            _ => whole,
+        };
+        self.write_stored(Store::Objects, &hash, &stored)
+    }
+
+    /// Stores an object under the hash of its content, reusing its compressed
+    /// bytes. Lets `migrate` rename objects of format 1 stores.
+    pub fn rehash_object(&self, hash: &str) -> Result<[u8; 32], Errors> {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut self.open_object(hash)?, &mut hasher)?;
+        let new = *hasher.finalize().as_bytes();
+        if self.is_stored(Store::Objects, &new)? {
+            return Ok(new);
+        }
+
+        let tmp = self.new_tmp()?;
+        let written = File::create(&tmp)
+            .map_err(Errors::from)
+            .and_then(|mut file| {
+                io::copy(&mut self.open_stored(Store::Objects, hash)?, &mut file)?;
+                Ok(file.sync_all()?)
+            });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.place(Store::Objects, &tmp, &new)?;
+
+        Ok(new)
+    }
+
+    pub fn save_snapshot(&self, content: Vec<u8>) -> Result<[u8; 32], Errors> {
+        self.store_bytes(Store::Snapshots, &content, self.compression()?)
     }
 
     pub fn load_object(&self, hash: String) -> Result<Vec<u8>, Errors> {
-        let file_path = self.objects_path().join(&hash[..3]).join(&hash[3..]);
-        let data = fs::read(file_path)?;
         let mut content = Vec::new();
-        {
-            let mut decoder = Decoder::new(&data[..])?;
-            decoder.read_to_end(&mut content)?;
-        }
+        self.open_object(&hash)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Decompressing reader over a stored object.
+    pub fn open_object(&self, hash: &str) -> Result<impl Read + use<>, Errors> {
+        self.decompress(self.open_stored(Store::Objects, hash)?)
+    }
+
+    /// Snapshot exactly as it was stored, which is what its hash covers.
+    pub fn load_snapshot_bytes(&self, hash: &str) -> Result<Vec<u8>, Errors> {
+        let mut content = Vec::new();
+        self.decompress(self.open_stored(Store::Snapshots, hash)?)?
+            .read_to_end(&mut content)?;
         Ok(content)
     }
 
     pub fn load_snapshot(&self, hash: String) -> Result<Snapshot, Errors> {
-        let dir = &hash[..3];
-        let filename = &hash[3..];
+        let meta: Snapshot = serde_json::from_slice(&self.load_snapshot_bytes(&hash)?)?;
+        if meta.version > SNAPSHOT_FORMAT {
+            return Err(Errors::FormatTooNew("snapshot", meta.version));
+        }
 
-        let meta_path = self.snapshots_path().join(dir).join(filename);
-        let meta_data_cmp = fs::read(meta_path)?;
-        let mut meta_data = Vec::new();
-        {
-            let mut decoder = Decoder::new(&meta_data_cmp[..])?;
-            decoder.read_to_end(&mut meta_data)?;
+        Ok(meta)
+    }
+
+    pub fn has_object(&self, hash: &str) -> Result<bool, Errors> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hash, &mut bytes)?;
+        self.is_stored(Store::Objects, &bytes)
+    }
+
+    /// Removes a loose snapshot, packed ones are left to `clean`.
+    pub fn remove_loose_snapshot(&self, hash: &str) -> Result<bool, Errors> {
+        let path = self.snapshots_path().join(&hash[..3]).join(&hash[3..]);
+        if !path.exists() {
+            return Ok(false);
         }
+        fs::remove_file(path)?;
+        Ok(true)
+    }
 
-        let meta: Snapshot = serde_json::from_slice(&meta_data)?;
+    fn store(&self, store: Store) -> (PathBuf, PathBuf, &OnceLock<Vec<PackIndex>>) {
+        match store {
+            Store::Objects => (
+                self.objects_path(),
+                self.object_packs_path(),
+                &self.object_packs,
+            ),
+            Store::Snapshots => (
+                self.snapshots_path(),
+                self.snapshot_packs_path(),
+                &self.snapshot_packs,
+            ),
+        }
+    }
 
-        Ok(meta)
+    fn packs(&self, store: Store) -> Result<&[PackIndex], Errors> {
+        let (_, dir, cache) = self.store(store);
+        if let Some(packs) = cache.get() {
+            return Ok(packs);
+        }
+        let packs = read_packs(&dir)?;
+        Ok(cache.get_or_init(|| packs))
+    }
+
+    fn find_packed(&self, store: Store, hash: &[u8; 32]) -> Result<bool, Errors> {
+        Ok(self.packs(store)?.iter().any(|p| p.contains(hash)))
+    }
+
+    fn is_stored(&self, store: Store, hash: &[u8; 32]) -> Result<bool, Errors> {
+        let (base, _, _) = self.store(store);
+        let name = hex::encode(hash);
+        if base.join(&name[..3]).join(&name[3..]).exists() {
+            return Ok(true);
+        }
+        self.find_packed(store, hash)
+    }
+
+    fn new_tmp(&self) -> Result<PathBuf, Errors> {
+        let tmp_dir = self.tmp_path();
+        fs::create_dir_all(&tmp_dir)?;
+        Ok(tmp_dir.join(Uuid::new_v4().to_string()))
+    }
+
+    /// In-memory content is hashed up front, so nothing is compressed or written
+    /// when the store already has it.
+    fn store_bytes(
+        &self,
+        store: Store,
+        content: &[u8],
+        compression: Compression,
+    ) -> Result<[u8; 32], Errors> {
+        let hash = *blake3::hash(content).as_bytes();
+        if self.is_stored(store, &hash)? {
+            return Ok(hash);
+        }
+
+        self.write_stored(store, &hash, &self.compress(content, compression)?)
+    }
+
+    fn compress(&self, content: &[u8], compression: Compression) -> Result<Vec<u8>, Errors> {
+        let compression = compression.for_content(&content[..content.len().min(HEAD_LEN)]);
+        let dictionary = self.encoder_dictionary(compression)?;
+        compress(content, compression, dictionary.as_deref())
+    }
+
+    fn write_stored(
+        &self,
+        store: Store,
+        hash: &[u8; 32],
+        stored: &[u8],
+    ) -> Result<[u8; 32], Errors> {
+        let tmp = self.new_tmp()?;
+        if let Err(e) = write_synced(&tmp, stored) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.place(store, &tmp, hash)?;
+
+        Ok(*hash)
+    }
+
+    /// Moves a synced temporary file to its place in the store, or drops it if
+    /// the store already has that hash.
+    fn place(&self, store: Store, tmp: &Path, hash: &[u8; 32]) -> Result<(), Errors> {
+        if self.is_stored(store, hash)? {
+            fs::remove_file(tmp)?;
+            return Ok(());
+        }
+
+        let (base, _, _) = self.store(store);
+        let name = hex::encode(hash);
+        let dir = base.join(&name[..3]);
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            sync_dir(&base)?;
+        }
+        rename_synced(tmp, &dir.join(&name[3..]))
+    }
+
+    /// Writes the compressed stream into a temporary file first and moves it into
+    /// place once the hash of the content is known.
+    fn store_from(
+        &self,
+        store: Store,
+        reader: &mut impl Read,
+        compression: Compression,
+    ) -> Result<[u8; 32], Errors> {
+        let mut head = Vec::with_capacity(HEAD_LEN);
+        reader.take(HEAD_LEN as u64).read_to_end(&mut head)?;
+        let compression = compression.for_content(&head);
+        let dictionary = self.encoder_dictionary(compression)?;
+
+        let tmp = self.new_tmp()?;
+
+        let written = File::create(&tmp).map_err(Errors::from).and_then(|file| {
+            let mut writer = HashWriter::new(CompressWriter::new(
+                BufWriter::new(file),
+                compression,
+                dictionary.as_deref(),
+            )?);
+            io::copy(&mut head.as_slice().chain(reader), &mut writer)?;
+            let hash = writer.finalize();
+            writer
+                .inner
+                .finish()?
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            Ok(hash)
+        });
+        let hash = match written {
+            Ok(hash) => hash,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+
+        self.place(store, &tmp, &hash)?;
+
+        Ok(hash)
+    }
+
+    /// Stored (compressed) stream of a loose file, falling back to the packs.
+    fn open_stored(&self, store: Store, hash: &str) -> Result<Box<dyn Read>, Errors> {
+        let (base, _, _) = self.store(store);
+        match File::open(base.join(&hash[..3]).join(&hash[3..])) {
+            Ok(file) => return Ok(Box::new(file)),
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hash, &mut bytes)?;
+        for pack in self.packs(store)? {
+            if let Some(entry) = pack.open_entry(&bytes)? {
+                return Ok(Box::new(entry));
+            }
+        }
+
+        Err(Errors::ObjectNotFound(hash.to_string()))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Store {
+    Objects,
+    Snapshots,
+}
+
+/// Passes writes through while hashing them.
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    fn finalize(&self) -> [u8; 32] {
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+impl AppContext {
+    /// Empty store in a temporary directory, which is removed when the returned
+    /// guard is dropped, also when a test fails.
+    pub fn scratch() -> (tempfile::TempDir, Self) {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = Self::new(Some(dir.path().to_path_buf())).unwrap();
+        ctx.make_root_dir().unwrap();
+        (dir, ctx)
     }
 }
@@ -0,0 +1,148 @@
+use std::io::{self, BufReader, Read, Write};
+
+use zstd::{Decoder, Encoder};
+
+use super::Errors;
+
+/// Starts every object stored as a delta, followed by its depth, the hash of
+/// its base and a zstd frame that uses the base content as prefix.
+const DELTA_MAGIC: &[u8; 4] = b"DNLD";
+
+const HEADER_LEN: usize = DELTA_MAGIC.len() + 1 + 32;
+
+/// Longest chain of deltas. The version after it is stored whole again, so
+/// reading an object never resolves more than this many bases.
+pub const MAX_DEPTH: u8 = 8;
+
+/// A delta is only kept when it is at most this fraction of the whole object.
+pub const MAX_RATIO: usize = 2;
+
+pub struct DeltaHeader {
+    /// 1 for a delta against a whole object.
+    pub depth: u8,
+    pub base: [u8; 32],
+}
+
+/// Reads the header of a stored object. Returns the bytes read when it is not
+/// a delta, they belong to the object.
+pub fn read_header(stored: &mut impl Read) -> Result<(Option<DeltaHeader>, Vec<u8>), Errors> {
+    let mut head = Vec::with_capacity(HEADER_LEN);
+    stored.take(HEADER_LEN as u64).read_to_end(&mut head)?;
+    if head.len() < HEADER_LEN || !head.starts_with(DELTA_MAGIC) {
+        return Ok((None, head));
+    }
+
+    let mut base = [0u8; 32];
+    base.copy_from_slice(&head[DELTA_MAGIC.len() + 1..]);
+    let header = DeltaHeader {
+        depth: head[DELTA_MAGIC.len()],
+        base,
+    };
+    Ok((Some(header), Vec::new()))
+}
+
+/// Stored form of `content` as a delta against `base`, the content of the
+/// object `header.base` names.
+pub fn encode(
+    content: &[u8],
+    base: &[u8],
+    header: &DeltaHeader,
+    level: i32,
+) -> Result<Vec<u8>, Errors> {
+    let mut stored = Vec::with_capacity(HEADER_LEN);
+    stored.extend_from_slice(DELTA_MAGIC);
+    stored.push(header.depth);
+    stored.extend_from_slice(&header.base);
+
+    let mut encoder = Encoder::with_ref_prefix(stored, level, base)?;
+    // Matches reach back over the whole base.
+    encoder.window_log(window_log(base.len() + content.len()))?;
+    encoder.write_all(content)?;
+    Ok(encoder.finish()?)
+}
+
+/// Content of a delta whose header was already read from `stored`.
+pub fn decode(stored: impl Read, base: &[u8]) -> Result<Vec<u8>, Errors> {
+    let mut decoder = Decoder::with_ref_prefix(BufReader::new(stored), base)?;
+    let mut content = Vec::new();
+    io::copy(&mut decoder, &mut content)?;
+    Ok(content)
+}
+
+fn window_log(size: usize) -> u32 {
+    size.max(1)
+        .next_power_of_two()
+        .trailing_zeros()
+        .clamp(10, 27)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{compression::Compression, context::AppContext};
+
+    fn text(version: usize) -> Vec<u8> {
+        (0..4000)
+            .map(|line| format!("line {} of a file that slowly changes\n", line * 7 % 1009))
+            .chain(std::iter::once(format!("version {}\n", version)))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn header_and_content_round_trip() {
+        let (base, content) = (text(0), text(1));
+        let header = DeltaHeader {
+            depth: 3,
+            base: [7; 32],
+        };
+        let stored = encode(&content, &base, &header, 3).unwrap();
+        assert!(stored.len() < content.len() / 10);
+
+        let mut reader = stored.as_slice();
+        let (read, consumed) = read_header(&mut reader).unwrap();
+        let read = read.unwrap();
+        assert!(consumed.is_empty());
+        assert_eq!((read.depth, read.base), (3, [7; 32]));
+        assert_eq!(decode(reader, &base).unwrap(), content);
+    }
+
+    #[test]
+    fn other_objects_have_no_header() {
+        for stored in [
+            &b"plain object bytes, long enough for a header......"[..],
+            b"DNLD",
+            b"",
+        ] {
+            let mut reader = stored;
+            let (header, consumed) = read_header(&mut reader).unwrap();
+            assert!(header.is_none());
+            assert_eq!(consumed, &stored[..stored.len().min(HEADER_LEN)]);
+        }
+
+        let mut not_delta = b"ZSTD".to_vec();
+        not_delta.resize(HEADER_LEN, 0);
+        assert!(read_header(&mut not_delta.as_slice()).unwrap().0.is_none());
+    }
+
+    #[test]
+    fn chains_stop_at_max_depth() {
+        let (_dir, ctx) = AppContext::scratch();
+
+        let mut base = ctx.save_object(text(0)).unwrap();
+        let mut depths = Vec::new();
+        for version in 1..=MAX_DEPTH as usize + 2 {
+            let hash = ctx
+                .save_object_delta(text(version), &base, Compression::default())
+                .unwrap();
+            let hex = hex::encode(hash);
+            depths.push(ctx.delta_header(&hex).unwrap().map_or(0, |h| h.depth));
+            assert_eq!(ctx.load_object(hex).unwrap(), text(version));
+            base = hash;
+        }
+
+        let mut expected: Vec<u8> = (1..=MAX_DEPTH).collect();
+        expected.extend([0, 1]);
+        assert_eq!(depths, expected);
+    }
+}
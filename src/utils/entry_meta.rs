@@ -0,0 +1,192 @@
+use std::{
+    ffi::CString,
+    fs::Metadata,
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use super::{Errors, tree_codec::Reader};
+
+/// Extended attribute names and values, sorted by name.
+pub type Xattrs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Modification time, ownership and extended attributes of a tree entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Xattrs,
+    /// For the second and later paths of a hardlink group, the first path of the
+    /// group relative to the root of the saved tree, see `check_link`.
+    pub link: Option<PathBuf>,
+}
+
+impl EntryMeta {
+    /// Reads the metadata of `path` without following symlinks.
+    pub fn read(path: &Path, meta: &Metadata) -> Result<Self, Errors> {
+        Ok(Self {
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            xattrs: read_xattrs(path)?,
+            link: None,
+        })
+    }
+
+    /// Record layout: mtime (8), mtime_nsec (4), uid (4), gid (4), then for every
+    /// xattr its name length (2), name, value length (4) and value. Big endian.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.mtime.to_be_bytes());
+        out.extend_from_slice(&self.mtime_nsec.to_be_bytes());
+        out.extend_from_slice(&self.uid.to_be_bytes());
+        out.extend_from_slice(&self.gid.to_be_bytes());
+        for (name, value) in &self.xattrs {
+            out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            out.extend_from_slice(name);
+            out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            out.extend_from_slice(value);
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(data);
+        let mut meta = Self {
+            mtime: reader.i64()?,
+            mtime_nsec: reader.u32()?,
+            uid: reader.u32()?,
+            gid: reader.u32()?,
+            xattrs: Vec::new(),
+            link: None,
+        };
+
+        while !reader.is_empty() {
+            let name_len = reader.u16()? as usize;
+            let name = reader.take(name_len)?.to_vec();
+            let value_len = reader.u32()? as usize;
+            let value = reader.take(value_len)?.to_vec();
+            meta.xattrs.push((name, value));
+        }
+
+        Some(meta)
+    }
+
+    /// Restores the metadata on `path`. Ownership is only restored when running as
+    /// root, and attributes the filesystem or user can not set are skipped. The
+    /// mtime goes last, as setting the others does not touch it.
+    pub fn apply(&self, path: &Path) -> Result<(), Errors> {
+        let c_path =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| Errors::InternalError)?;
+
+        if unsafe { libc::geteuid() } == 0 {
+            check(unsafe { libc::lchown(c_path.as_ptr(), self.uid, self.gid) })?;
+        }
+
+        for (name, value) in &self.xattrs {
+            let Ok(c_name) = CString::new(name.clone()) else {
+                continue;
+            };
+            let result = unsafe {
+                libc::lsetxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            };
+            if let Err(e) = check(result) {
+                match e.raw_os_error() {
+                    Some(libc::EPERM | libc::EACCES | libc::ENOTSUP) => {}
+                    _ => return Err(e.into()),
+                }
+            }
+        }
+
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: self.mtime as libc::time_t,
+                tv_nsec: self.mtime_nsec as libc::c_long,
+            },
+        ];
+        check(unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> Result<(), io::Error> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn read_xattrs(path: &Path) -> Result<Xattrs, Errors> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errors::InternalError)?;
+
+    let Some(names) =
+        read_sized(|buf, len| unsafe { libc::llistxattr(c_path.as_ptr(), buf.cast(), len) })?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name).map_err(|_| Errors::InternalError)?;
+        let value = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        })?;
+        // The attribute may have been removed since it was listed.
+        if let Some(value) = value {
+            xattrs.push((name.to_vec(), value));
+        }
+    }
+
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+/// Calls a size-query style xattr function until the buffer fits. `None` when
+/// the filesystem has no xattr support or the attribute is gone.
+fn read_sized(call: impl Fn(*mut u8, usize) -> libc::ssize_t) -> Result<Option<Vec<u8>>, Errors> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTSUP | libc::ENODATA) => Ok(None),
+                _ => Err(e.into()),
+            };
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let read = call(buf.as_mut_ptr(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(Some(buf));
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            // Grew between the two calls.
+            Some(libc::ERANGE) => continue,
+            Some(libc::ENOTSUP | libc::ENODATA) => return Ok(None),
+            _ => return Err(e.into()),
+        }
+    }
+}
@@ -65,6 +65,56 @@ pub enum Errors {
     #[error("Provided path \"{0}\" is inside an existing project path")]
     ParentPath(String),
 
+    #[error("Merge left {0} conflicts, no snapshot was saved")]
+    MergeConflicts(usize),
+    #[error("Cell has {0} unsaved changes, save them first or merge with --force")]
+    UnsavedChanges(usize),
+
+    #[error("No retention policy given")]
+    NoRetentionPolicy,
+
+    #[error("Retention policy of \"{0}\" keeps no snapshots")]
+    EmptyRetention(String),
+
+    #[error("Object \"{0}\" is not in the store")]
+    ObjectNotFound(String),
+
+    #[error("Pack file \"{0}\" is corrupt")]
+    CorruptPack(PathBuf),
+
+    #[error("Store check found {0} problems")]
+    StoreDamaged(usize),
+
+    #[error("Store is locked by {0}, use --wait to wait for it")]
+    StoreLocked(String),
+
+    #[error("Tree object is malformed")]
+    MalformedTree,
+
+    #[error("Invalid file name in tree: \"{0}\"")]
+    InvalidName(String),
+
+    #[error("Invalid hardlink target in tree: \"{0}\"")]
+    InvalidLink(String),
+
+    #[error("The {0} format {1} of this store is newer than this denali supports")]
+    FormatTooNew(&'static str, u32),
+
+    #[error("Store uses an older format, run `denali migrate` first")]
+    NeedsMigration,
+
+    #[error("The stores use object formats {0} and {1}, run `denali migrate` on the older one")]
+    ObjectFormatMismatch(u32, u32),
+
+    #[error("Dictionary {0} is not in the store")]
+    DictionaryNotFound(u32),
+
+    #[error("Invalid store config .denali/config: {0}")]
+    InvalidStoreConfig(String),
+
+    #[error("Could not train a dictionary: {0}")]
+    DictionaryTraining(String),
+
     #[error("No matches found")]
     NoMatches,
 
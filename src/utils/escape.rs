@@ -0,0 +1,26 @@
+use std::{os::unix::ffi::OsStrExt, path::Path};
+
+/// Renders a path for the terminal without losing information: bytes that are
+/// not valid UTF-8 become `\xNN`, control characters and backslashes are
+/// escaped, so two different names never print the same.
+pub fn escape_path(path: &Path) -> String {
+    let mut out = String::new();
+
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+
+    out
+}
@@ -8,6 +8,7 @@ pub enum FileType {
     Symlink,
     Socket,
     Cell,
+    Chunked,
     Unknown,
 }
 
@@ -22,7 +23,13 @@ impl FileType {
             0xA000 => FileType::Symlink,
             0xC000 => FileType::Socket,
             0xB000 => FileType::Cell,
+            0x9000 => FileType::Chunked,
             _ => FileType::Unknown,
         }
     }
+
+    /// Regular file, stored either as one blob or as a chunk list.
+    pub fn is_file(&self) -> bool {
+        matches!(self, FileType::Regular | FileType::Chunked)
+    }
 }
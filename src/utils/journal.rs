@@ -0,0 +1,132 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+};
+
+use uuid::Uuid;
+
+use super::{Errors, atomic::sync_dir, context::AppContext};
+
+/// Snapshots written by a save that has not reached the manifests yet. If the
+/// save fails or the process dies, the journal tells which snapshots to drop.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    hashes: Vec<String>,
+}
+
+impl Journal {
+    pub fn begin(ctx: &AppContext) -> Result<Self, Errors> {
+        let dir = ctx.journal_path();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(Uuid::new_v4().to_string());
+        let file = File::create(&path)?;
+        sync_dir(&dir)?;
+
+        Ok(Self {
+            path,
+            file,
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn record(&mut self, hash: &[u8; 32]) -> Result<(), Errors> {
+        let hex = hex::encode(hash);
+        writeln!(self.file, "{}", hex)?;
+        self.file.sync_data()?;
+        self.hashes.push(hex);
+        Ok(())
+    }
+
+    /// Drops the journal on success, rolls the save back on failure.
+    pub fn finish<T>(self, ctx: &AppContext, result: Result<T, Errors>) -> Result<T, Errors> {
+        match result {
+            Ok(value) => {
+                fs::remove_file(&self.path)?;
+                Ok(value)
+            }
+            Err(e) => {
+                remove_unreferenced(ctx, &self.hashes)?;
+                fs::remove_file(&self.path)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Rolls back saves that were interrupted before their manifests were written
+/// and drops the temporary files they left behind. Returns how many snapshots
+/// were removed. Callers hold the exclusive lock, so no write is in flight.
+pub fn recover(ctx: &AppContext) -> Result<usize, Errors> {
+    clear_tmp(ctx)?;
+
+    let dir = ctx.journal_path();
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let hashes: Vec<String> = fs::read_to_string(&path)?
+            .lines()
+            .filter(|l| l.len() == 64)
+            .map(|l| l.to_string())
+            .collect();
+        removed += remove_unreferenced(ctx, &hashes)?;
+        fs::remove_file(&path)?;
+    }
+
+    Ok(removed)
+}
+
+fn clear_tmp(ctx: &AppContext) -> Result<(), Errors> {
+    let dir = ctx.tmp_path();
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Snapshots that any manifest still points to are kept, even if they are
+/// marked as deleted.
+fn remove_unreferenced(ctx: &AppContext, hashes: &[String]) -> Result<usize, Errors> {
+    if hashes.is_empty() {
+        return Ok(0);
+    }
+
+    let mut referenced: HashSet<String> = HashSet::new();
+    let manifest = ctx.load_main_manifest()?;
+    for project_ref in manifest.projects.values() {
+        let project_manifest = ctx.load_project_manifest(project_ref.manifest.clone())?;
+        for snapshot in project_manifest.snapshots.values() {
+            referenced.insert(snapshot.hash.clone());
+        }
+        for cell_ref in project_manifest.cells.values() {
+            for snapshot in cell_ref.snapshots.values() {
+                referenced.insert(snapshot.hash.clone());
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for hash in hashes {
+        if !referenced.contains(hash) && ctx.remove_loose_snapshot(hash)? {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,112 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use super::Errors;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// Advisory lock on the store root, released when dropped, and by the kernel
+/// when its holder dies. Writers record their pid in the lock file so a busy
+/// store can name who holds it. The pid may be stale, it is only reported.
+pub struct StoreLock {
+    file: File,
+    mode: LockMode,
+}
+
+impl StoreLock {
+    pub fn acquire(path: &Path, mode: LockMode, wait: Option<Duration>) -> Result<Self, Errors> {
+        let started = Instant::now();
+
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+
+            let result = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock(),
+            };
+
+            match result {
+                Ok(()) => {
+                    let mut lock = Self { file, mode };
+                    lock.record_holder()?;
+                    return Ok(lock);
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+
+            let waited_out = match wait {
+                Some(wait) => started.elapsed() >= wait,
+                None => true,
+            };
+            if waited_out {
+                return Err(Errors::StoreLocked(match read_holder(path) {
+                    Some(pid) if process_alive(pid) => format!("process {}", pid),
+                    _ => "another denali process".to_string(),
+                }));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Writers leave their pid, readers clear whatever a crashed writer left.
+    fn record_holder(&mut self) -> Result<(), Errors> {
+        match self.mode {
+            LockMode::Exclusive => {
+                self.file.set_len(0)?;
+                self.file.rewind()?;
+                write!(self.file, "{}", process::id())?;
+                self.file.flush()?;
+            }
+            LockMode::Shared => {
+                if self.file.metadata()?.len() > 0 {
+                    self.file.set_len(0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if self.mode == LockMode::Exclusive {
+            let _ = self.file.set_len(0);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_holder(path: &Path) -> Option<u32> {
+    let mut content = String::new();
+    File::open(path).ok()?.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return true;
+    };
+    // Signal 0 only checks whether the process exists.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
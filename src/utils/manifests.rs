@@ -69,8 +69,18 @@ pub struct ProjectManifest {
     pub cells: HashMap<String, CellRef>,
 }
 
+/// Version written into new snapshots.
+pub const SNAPSHOT_FORMAT: u32 = 2;
+
+fn format_1() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Snapshot {
+    /// Snapshots without a version predate it and are format 1.
+    #[serde(default = "format_1")]
+    pub version: u32,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub description: String,
     pub timestamp: DateTime<Utc>,
@@ -1,9 +1,23 @@
+pub mod atomic;
+pub mod chunker;
+pub mod compression;
 pub mod config;
 pub mod context;
+pub mod delta;
+pub mod entry_meta;
 pub mod errors;
+pub mod escape;
 pub mod file_type;
+pub mod journal;
+pub mod lock;
 pub mod manifests;
+pub mod pack;
 pub mod parse_name;
+pub mod pool;
+pub mod stat_cache;
+pub mod store_config;
+pub mod text_diff;
+pub mod tree_codec;
 
 pub use config::*;
 pub use errors::Errors;
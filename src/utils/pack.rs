@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, hash_map},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Take, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    Errors,
+    atomic::{write_atomic, write_atomic_with},
+};
+
+const PACK_MAGIC: &[u8; 4] = b"DNLP";
+const INDEX_MAGIC: &[u8; 4] = b"DNLI";
+const RECORD_SIZE: usize = 48;
+
+/// An object to pack and where its stored bytes are read from, so packs are
+/// written without holding their content in memory.
+pub struct PackEntry {
+    pub hash: [u8; 32],
+    size: u64,
+    source: Source,
+}
+
+enum Source {
+    Loose(PathBuf),
+    /// Pack file and offset of the entry in it.
+    Packed(PathBuf, u64),
+}
+
+impl PackEntry {
+    pub fn loose(hash: [u8; 32], path: PathBuf) -> Result<Self, Errors> {
+        Ok(Self {
+            hash,
+            size: fs::metadata(&path)?.len(),
+            source: Source::Loose(path),
+        })
+    }
+}
+
+/// Sorted index of a pack file. Every record is `hash(32) offset(8 BE) size(8 BE)`.
+pub struct PackIndex {
+    pack: PathBuf,
+    records: Vec<u8>,
+}
+
+impl PackIndex {
+    pub fn read(index_path: &Path) -> Result<Self, Errors> {
+        let data = fs::read(index_path)?;
+        if data.len() < 8 || &data[..4] != INDEX_MAGIC {
+            return Err(Errors::CorruptPack(index_path.to_path_buf()));
+        }
+
+        let count = u32::from_be_bytes(data[4..8].try_into()?) as usize;
+        let records = data[8..].to_vec();
+        if records.len() != count * RECORD_SIZE {
+            return Err(Errors::CorruptPack(index_path.to_path_buf()));
+        }
+
+        Ok(Self {
+            pack: index_path.with_extension("pack"),
+            records,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.records.len() / RECORD_SIZE
+    }
+
+    pub fn pack_path(&self) -> &Path {
+        &self.pack
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.pack.with_extension("idx")
+    }
+
+    fn hash_at(&self, i: usize) -> &[u8] {
+        &self.records[i * RECORD_SIZE..i * RECORD_SIZE + 32]
+    }
+
+    fn location_at(&self, i: usize) -> Result<(u64, u64), Errors> {
+        let record = &self.records[i * RECORD_SIZE..(i + 1) * RECORD_SIZE];
+        let offset = u64::from_be_bytes(record[32..40].try_into()?);
+        let size = u64::from_be_bytes(record[40..48].try_into()?);
+        Ok((offset, size))
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = [u8; 32]> + '_ {
+        (0..self.len()).map(|i| {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(self.hash_at(i));
+            hash
+        })
+    }
+
+    fn position(&self, hash: &[u8; 32]) -> Option<usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.hash_at(mid).cmp(&hash[..]) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.position(hash).is_some()
+    }
+
+    /// Stored (compressed) bytes of an entry, or `None` if this pack does not have it.
+    pub fn open_entry(&self, hash: &[u8; 32]) -> Result<Option<Take<File>>, Errors> {
+        let Some(i) = self.position(hash) else {
+            return Ok(None);
+        };
+        let (offset, size) = self.location_at(i)?;
+
+        let mut file = File::open(&self.pack)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(Some(file.take(size)))
+    }
+
+    /// Hash and stored size of every entry, without reading the pack.
+    pub fn sizes(&self) -> Result<Vec<([u8; 32], u64)>, Errors> {
+        self.hashes()
+            .enumerate()
+            .map(|(i, hash)| Ok((hash, self.location_at(i)?.1)))
+            .collect()
+    }
+
+    /// Location of every entry of the pack in index order.
+    pub fn entries(&self) -> Result<Vec<PackEntry>, Errors> {
+        let mut file = File::open(&self.pack)?;
+        let mut magic = [0u8; 4];
+        if file.read_exact(&mut magic).is_err() || &magic != PACK_MAGIC {
+            return Err(Errors::CorruptPack(self.pack.clone()));
+        }
+        let pack_len = file.metadata()?.len();
+
+        let mut entries = Vec::with_capacity(self.len());
+        for (i, hash) in self.hashes().enumerate() {
+            let (offset, size) = self.location_at(i)?;
+            if offset.checked_add(size).is_none_or(|end| end > pack_len) {
+                return Err(Errors::CorruptPack(self.pack.clone()));
+            }
+            entries.push(PackEntry {
+                hash,
+                size,
+                source: Source::Packed(self.pack.clone(), offset),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    pub fn remove(&self) -> Result<(), Errors> {
+        fs::remove_file(self.index_path())?;
+        fs::remove_file(&self.pack)?;
+        Ok(())
+    }
+}
+
+pub fn read_packs(dir: &Path) -> Result<Vec<PackIndex>, Errors> {
+    let mut packs = Vec::new();
+    if !dir.is_dir() {
+        return Ok(packs);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "idx") {
+            packs.push(PackIndex::read(&path)?);
+        }
+    }
+
+    Ok(packs)
+}
+
+/// Writes the entries into a new pack named after the hash of its index and
+/// returns the path of the pack file. Their bytes are copied straight from the
+/// loose files and packs they live in.
+pub fn write_pack(dir: &Path, mut entries: Vec<PackEntry>) -> Result<PathBuf, Errors> {
+    entries.sort_by_key(|e| e.hash);
+    entries.dedup_by(|a, b| a.hash == b.hash);
+
+    let mut records = Vec::with_capacity(entries.len() * RECORD_SIZE);
+    let mut offset = PACK_MAGIC.len() as u64;
+    for entry in &entries {
+        records.extend_from_slice(&entry.hash);
+        records.extend_from_slice(&offset.to_be_bytes());
+        records.extend_from_slice(&entry.size.to_be_bytes());
+        offset += entry.size;
+    }
+
+    let mut index = INDEX_MAGIC.to_vec();
+    index.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    index.extend_from_slice(&records);
+
+    let name = format!("pack-{}", blake3::hash(&records).to_hex());
+    fs::create_dir_all(dir)?;
+    let pack_path = dir.join(format!("{}.pack", name));
+
+    write_atomic_with(&pack_path, |pack| {
+        pack.write_all(PACK_MAGIC)?;
+        let mut sources: HashMap<PathBuf, File> = HashMap::new();
+        for entry in &entries {
+            match &entry.source {
+                Source::Loose(path) => copy_entry(File::open(path)?, entry.size, pack, path)?,
+                Source::Packed(path, offset) => {
+                    let source = match sources.entry(path.clone()) {
+                        hash_map::Entry::Occupied(open) => open.into_mut(),
+                        hash_map::Entry::Vacant(slot) => slot.insert(File::open(path)?),
+                    };
+                    source.seek(SeekFrom::Start(*offset))?;
+                    copy_entry(source, entry.size, pack, path)?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    write_atomic(&dir.join(format!("{}.idx", name)), &index)?;
+
+    Ok(pack_path)
+}
+
+fn copy_entry(source: impl Read, size: u64, pack: &mut File, path: &Path) -> Result<(), Errors> {
+    if io::copy(&mut source.take(size), pack)? != size {
+        return Err(Errors::CorruptPack(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Rewrites every pack that holds entries rejected by `keep`, copying the kept
+/// entries over from the old pack. Returns how many entries were dropped.
+pub fn filter_packs(dir: &Path, keep: impl Fn(&[u8; 32]) -> bool) -> Result<usize, Errors> {
+    let mut dropped = 0;
+
+    for pack in read_packs(dir)? {
+        if pack.hashes().all(|h| keep(&h)) {
+            continue;
+        }
+
+        let entries = pack.entries()?;
+        let before = entries.len();
+        let kept: Vec<PackEntry> = entries.into_iter().filter(|e| keep(&e.hash)).collect();
+        dropped += before - kept.len();
+
+        let new_pack = if kept.is_empty() {
+            None
+        } else {
+            Some(write_pack(dir, kept)?)
+        };
+        if new_pack.as_deref() != Some(pack.pack_path()) {
+            pack.remove()?;
+        }
+    }
+
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs one loose file per content and returns the hashes in input order.
+    fn pack_loose(dir: &Path, contents: &[&[u8]]) -> Vec<[u8; 32]> {
+        let mut entries = Vec::new();
+        let mut hashes = Vec::new();
+        for content in contents {
+            let hash = *blake3::hash(content).as_bytes();
+            let path = dir.join(hex::encode(hash));
+            fs::write(&path, content).unwrap();
+            entries.push(PackEntry::loose(hash, path).unwrap());
+            hashes.push(hash);
+        }
+        write_pack(&dir.join("pack"), entries).unwrap();
+        hashes
+    }
+
+    fn read_entry(index: &PackIndex, hash: &[u8; 32]) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        index
+            .open_entry(hash)
+            .unwrap()?
+            .read_to_end(&mut data)
+            .unwrap();
+        Some(data)
+    }
+
+    #[test]
+    fn index_finds_every_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let contents: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; i as usize * 7]).collect();
+        let slices: Vec<&[u8]> = contents.iter().map(Vec::as_slice).collect();
+        let hashes = pack_loose(dir, &slices);
+
+        let packs = read_packs(&dir.join("pack")).unwrap();
+        assert_eq!(packs.len(), 1);
+        let index = &packs[0];
+        for (hash, content) in hashes.iter().zip(&contents) {
+            assert!(index.contains(hash));
+            assert_eq!(read_entry(index, hash).as_ref(), Some(content));
+        }
+
+        let missing = *blake3::hash(b"not packed").as_bytes();
+        assert!(!index.contains(&missing));
+        assert!(read_entry(index, &missing).is_none());
+    }
+
+    #[test]
+    fn filter_copies_kept_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let hashes = pack_loose(dir, &[b"keep me", b"drop me", b"and me too"]);
+        let pack_dir = dir.join("pack");
+
+        let dropped = filter_packs(&pack_dir, |h| h == &hashes[0]).unwrap();
+        assert_eq!(dropped, 2);
+
+        let packs = read_packs(&pack_dir).unwrap();
+        assert_eq!(packs.len(), 1);
+        assert_eq!(packs[0].hashes().collect::<Vec<_>>(), vec![hashes[0]]);
+        assert_eq!(read_entry(&packs[0], &hashes[0]).unwrap(), b"keep me");
+
+        filter_packs(&pack_dir, |_| false).unwrap();
+        assert!(read_packs(&pack_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_index_is_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        pack_loose(dir, &[b"one", b"two"]);
+        let index_path = read_packs(&dir.join("pack")).unwrap()[0].index_path();
+
+        let data = fs::read(&index_path).unwrap();
+        fs::write(&index_path, &data[..data.len() - 1]).unwrap();
+        assert!(matches!(
+            PackIndex::read(&index_path),
+            Err(Errors::CorruptPack(_))
+        ));
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    panic,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+};
+
+use super::Errors;
+
+/// Runs `f` over `items` on up to `jobs` scoped threads and returns the results
+/// in the order of `items`. Workers stop picking up new items after an error.
+pub fn parallel_map<T, R, F>(jobs: usize, items: &[T], f: F) -> Result<Vec<R>, Errors>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, Errors> + Sync,
+{
+    let workers = jobs.min(items.len());
+    if workers <= 1 {
+        return items.iter().map(&f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    let finished = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= items.len() {
+                            break;
+                        }
+                        match f(&items[i]) {
+                            Ok(result) => done.push((i, result)),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(done)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|p| panic::resume_unwind(p)))
+            .collect::<Vec<_>>()
+    });
+
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    for worker in finished {
+        for (i, result) in worker? {
+            results[i] = Some(result);
+        }
+    }
+
+    results
+        .into_iter()
+        .map(|r| r.ok_or(Errors::InternalError))
+        .collect()
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Errors, atomic::write_atomic, context::AppContext};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct CachedStat {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+    ino: u64,
+}
+
+impl CachedStat {
+    fn from_meta(meta: &Metadata) -> Self {
+        Self {
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            ino: meta.ino(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    #[serde(flatten)]
+    stat: CachedStat,
+    hash: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    chunked: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Per-project map from file path to the stat data and object hash it had at the
+/// last save, so unchanged files do not have to be read again.
+pub struct StatCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    seen: HashSet<String>,
+    started: i64,
+}
+
+impl StatCache {
+    /// Loads the cache of a project. A missing or unreadable cache is just empty.
+    pub fn load(ctx: &AppContext, uuid: &str) -> Self {
+        let path = ctx.stat_cache_path(uuid);
+        let entries = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(i64::MAX);
+
+        Self {
+            path,
+            entries,
+            seen: HashSet::new(),
+            started,
+        }
+    }
+
+    /// Hash of `path` if its stat data is unchanged and the object is still stored.
+    pub fn lookup(
+        &mut self,
+        ctx: &AppContext,
+        path: &Path,
+        meta: &Metadata,
+    ) -> Result<Option<([u8; 32], bool)>, Errors> {
+        let key = path.to_string_lossy().to_string();
+        let Some(entry) = self.entries.get(&key) else {
+            return Ok(None);
+        };
+        if entry.stat != CachedStat::from_meta(meta) || !ctx.has_object(&entry.hash)? {
+            return Ok(None);
+        }
+
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(&entry.hash, &mut hash)?;
+        let chunked = entry.chunked;
+        self.seen.insert(key);
+        Ok(Some((hash, chunked)))
+    }
+
+    pub fn insert(&mut self, path: &Path, meta: &Metadata, hash: &[u8; 32], chunked: bool) {
+        let key = path.to_string_lossy().to_string();
+        // A file changed within the same second as the save could change again
+        // without its mtime moving, so it is not trusted until a later save.
+        if meta.mtime() >= self.started {
+            self.entries.remove(&key);
+            return;
+        }
+
+        self.entries.insert(
+            key.clone(),
+            CacheEntry {
+                stat: CachedStat::from_meta(meta),
+                hash: hex::encode(hash),
+                chunked,
+            },
+        );
+        self.seen.insert(key);
+    }
+
+    /// Writes the cache back, dropping files that no longer exist.
+    pub fn write(mut self) -> Result<(), Errors> {
+        let seen = &self.seen;
+        self.entries
+            .retain(|path, _| seen.contains(path) || Path::new(path).exists());
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&self.path, &serde_json::to_vec(&self.entries)?)
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::BTreeMap, fs, io::ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Errors, SNAPSHOT_FORMAT,
+    atomic::write_atomic,
+    compression::{Compression, MAX_DICT_ID, MIN_DICT_ID},
+    context::{AppContext, OBJECT_FORMAT},
+    lock::LockMode,
+    tree_codec::TREE_FORMAT,
+};
+
+/// Store-wide settings, kept in `.denali/config`. A store without the file
+/// predates it and holds format 1 objects only.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoreConfig {
+    pub tree_format: u32,
+    pub snapshot_format: u32,
+    /// Configs written before objects were named after their content lack it.
+    #[serde(default = "format_1")]
+    pub object_format: u32,
+    /// Default for every cell, `.denali.toml` can override it per cell.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Trained zstd dictionary of a project, by project uuid.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dictionaries: BTreeMap<String, u32>,
+}
+
+fn format_1() -> u32 {
+    1
+}
+
+impl StoreConfig {
+    /// Formats this version of denali writes.
+    pub fn current() -> Self {
+        Self {
+            tree_format: TREE_FORMAT.into(),
+            snapshot_format: SNAPSHOT_FORMAT,
+            object_format: OBJECT_FORMAT,
+            compression: Compression::default(),
+            dictionaries: BTreeMap::new(),
+        }
+    }
+
+    fn legacy() -> Self {
+        Self {
+            tree_format: 1,
+            snapshot_format: 1,
+            object_format: 1,
+            compression: Compression::default(),
+            dictionaries: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(ctx: &AppContext) -> Result<Self, Errors> {
+        match fs::read(ctx.store_config_path()) {
+            Ok(data) => Self::parse(&data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if ctx.main_manifest_path().exists() {
+                    Ok(Self::legacy())
+                } else {
+                    Ok(Self::current())
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parses a config, which may have been edited by hand, and refuses values
+    /// that would only fail once objects are written.
+    fn parse(data: &[u8]) -> Result<Self, Errors> {
+        let config: Self =
+            serde_json::from_slice(data).map_err(|e| Errors::InvalidStoreConfig(e.to_string()))?;
+
+        for (uuid, id) in &config.dictionaries {
+            if !(MIN_DICT_ID..MAX_DICT_ID).contains(id) {
+                return Err(Errors::InvalidStoreConfig(format!(
+                    "dictionary {} of project {} is not a trained dictionary id",
+                    id, uuid
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn write(&self, ctx: &AppContext) -> Result<(), Errors> {
+        write_atomic(&ctx.store_config_path(), &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Whether the store uses the formats this version writes. Settings such as
+    /// the compression do not matter.
+    pub fn is_current(&self) -> bool {
+        let current = Self::current();
+        self.tree_format == current.tree_format
+            && self.snapshot_format == current.snapshot_format
+            && self.object_format == current.object_format
+    }
+
+    /// Refuses stores written by a newer denali, and changes to stores that have
+    /// to go through `denali migrate` first. Reading older formats always works.
+    pub fn check(ctx: &AppContext, mode: LockMode) -> Result<(), Errors> {
+        let config = Self::load(ctx)?;
+        let current = Self::current();
+
+        if config.tree_format > current.tree_format {
+            return Err(Errors::FormatTooNew("tree", config.tree_format));
+        }
+        if config.snapshot_format > current.snapshot_format {
+            return Err(Errors::FormatTooNew("snapshot", config.snapshot_format));
+        }
+        if config.object_format > current.object_format {
+            return Err(Errors::FormatTooNew("object", config.object_format));
+        }
+        if mode == LockMode::Exclusive && !config.is_current() {
+            return Err(Errors::NeedsMigration);
+        }
+
+        Ok(())
+    }
+}
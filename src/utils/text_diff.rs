@@ -0,0 +1,339 @@
+const CONTEXT: usize = 3;
+const MAX_TRACE: usize = 50_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Bytes of a file looked at to tell binary from text.
+pub const BINARY_PROBE: usize = 8000;
+
+/// Whether content starting with `data` is binary. Only the first
+/// `BINARY_PROBE` bytes are checked, and a character cut at the end of a full
+/// probe does not count as invalid.
+pub fn is_binary(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(BINARY_PROBE)];
+    match std::str::from_utf8(head) {
+        Ok(_) => head.contains(&0),
+        Err(e) => e.error_len().is_some() || data.len() < BINARY_PROBE,
+    }
+}
+
+/// Unified diff of two texts, or `None` when the inputs are too large to diff by line.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> Option<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    let edits = edit_script(&a, &b)?;
+
+    let mut out = String::new();
+    out.push_str(&format!("--- {}\n+++ {}\n", old_label, new_label));
+
+    let changed: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal(..)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut i = 0;
+    while i < changed.len() {
+        let mut last = i;
+        while last + 1 < changed.len() && changed[last + 1] - changed[last] <= 2 * CONTEXT {
+            last += 1;
+        }
+
+        let start = changed[i].saturating_sub(CONTEXT);
+        let end = (changed[last] + CONTEXT + 1).min(edits.len());
+        out.push_str(&hunk(&a, &b, &edits, start, end));
+
+        i = last + 1;
+    }
+
+    Some(out)
+}
+
+fn hunk(a: &[&str], b: &[&str], edits: &[Edit], start: usize, end: usize) -> String {
+    let old_before = edits[..start]
+        .iter()
+        .filter(|e| !matches!(e, Edit::Insert(_)))
+        .count();
+    let new_before = edits[..start]
+        .iter()
+        .filter(|e| !matches!(e, Edit::Delete(_)))
+        .count();
+
+    let mut old_count = 0;
+    let mut new_count = 0;
+    let mut body = String::new();
+
+    for edit in &edits[start..end] {
+        match *edit {
+            Edit::Equal(x, _) => {
+                old_count += 1;
+                new_count += 1;
+                body.push_str(&format!(" {}\n", a[x]));
+            }
+            Edit::Delete(x) => {
+                old_count += 1;
+                body.push_str(&format!("-{}\n", a[x]));
+            }
+            Edit::Insert(y) => {
+                new_count += 1;
+                body.push_str(&format!("+{}\n", b[y]));
+            }
+        }
+    }
+
+    let old_start = if old_count > 0 {
+        old_before + 1
+    } else {
+        old_before
+    };
+    let new_start = if new_count > 0 {
+        new_before + 1
+    } else {
+        new_before
+    };
+
+    format!(
+        "@@ -{},{} +{},{} @@\n{}",
+        old_start, old_count, new_start, new_count, body
+    )
+}
+
+fn edit_script(a: &[&str], b: &[&str]) -> Option<Vec<Edit>> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize;
+    let mut v = vec![0isize; 2 * max + 2];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'outer: for d in 0..=max as isize {
+        if trace.len() * v.len() > MAX_TRACE {
+            return None;
+        }
+        trace.push(v.clone());
+
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    let mut edits = Vec::new();
+    let mut x = n;
+    let mut y = m;
+
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let idx = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert((y - 1) as usize));
+            } else {
+                edits.push(Edit::Delete((x - 1) as usize));
+            }
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    edits.reverse();
+    Some(edits)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergeStrategy {
+    Ours,
+    Theirs,
+    Union,
+}
+
+/// Line-level three-way merge. Returns the merged text and whether any conflict
+/// markers were written. Lines keep their terminators, so CRLF files and a
+/// missing final newline come out as they went in.
+pub fn merge3(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    labels: (&str, &str),
+    strategy: Option<MergeStrategy>,
+) -> Option<(String, bool)> {
+    let b: Vec<&str> = base.split_inclusive('\n').collect();
+    let o: Vec<&str> = ours.split_inclusive('\n').collect();
+    let t: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    let ours_map = base_matches(&edit_script(&b, &o)?, b.len());
+    let theirs_map = base_matches(&edit_script(&b, &t)?, b.len());
+
+    let mut text = String::new();
+    let mut conflicted = false;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    let eol = if ours.contains("\r\n") { "\r\n" } else { "\n" };
+
+    loop {
+        let sync = (i..b.len()).find_map(|x| match (ours_map[x], theirs_map[x]) {
+            (Some(oj), Some(tk)) if oj >= j && tk >= k => Some((x, oj, tk)),
+            _ => None,
+        });
+        let (bx, oj, tk) = sync.unwrap_or((b.len(), o.len(), t.len()));
+
+        let base_chunk = &b[i..bx];
+        let ours_chunk = &o[j..oj];
+        let theirs_chunk = &t[k..tk];
+
+        if ours_chunk == base_chunk {
+            text.extend(theirs_chunk.iter().copied());
+        } else if theirs_chunk == base_chunk || ours_chunk == theirs_chunk {
+            text.extend(ours_chunk.iter().copied());
+        } else {
+            match strategy {
+                Some(MergeStrategy::Ours) => text.extend(ours_chunk.iter().copied()),
+                Some(MergeStrategy::Theirs) => text.extend(theirs_chunk.iter().copied()),
+                Some(MergeStrategy::Union) => {
+                    push_block(&mut text, ours_chunk, eol);
+                    text.extend(theirs_chunk.iter().copied());
+                }
+                None => {
+                    conflicted = true;
+                    push_block(&mut text, &[&format!("<<<<<<< {}", labels.0)], eol);
+                    push_block(&mut text, ours_chunk, eol);
+                    push_block(&mut text, &["======="], eol);
+                    push_block(&mut text, theirs_chunk, eol);
+                    push_block(&mut text, &[&format!(">>>>>>> {}", labels.1)], eol);
+                }
+            }
+        }
+
+        if sync.is_none() {
+            break;
+        }
+
+        text.push_str(b[bx]);
+        i = bx + 1;
+        j = oj + 1;
+        k = tk + 1;
+    }
+
+    Some((text, conflicted))
+}
+
+/// Appends whole lines, ending the last one so the next block starts on a line
+/// of its own.
+fn push_block(text: &mut String, lines: &[&str], eol: &str) {
+    text.extend(lines.iter().copied());
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push_str(eol);
+    }
+}
+
+fn base_matches(edits: &[Edit], len: usize) -> Vec<Option<usize>> {
+    let mut map = vec![None; len];
+    for edit in edits {
+        if let Edit::Equal(x, y) = *edit {
+            map[x] = Some(y);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: (&str, &str) = ("ours", "theirs");
+
+    #[test]
+    fn is_binary_only_probes_the_head() {
+        assert!(!is_binary(b"plain text\n"));
+        assert!(is_binary(b"nul\0byte"));
+        assert!(is_binary(b"bad \xff utf8"));
+
+        let mut text = "aé".repeat(BINARY_PROBE);
+        assert!(!is_binary(text.as_bytes()));
+        text.push('\0');
+        assert!(!is_binary(text.as_bytes()));
+        assert!(is_binary(&text.as_bytes()[..2]));
+    }
+
+    #[test]
+    fn merge3_takes_changes_from_both_sides() {
+        let merged = merge3("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n", LABELS, None);
+        assert_eq!(merged, Some(("A\nb\nC\n".to_string(), false)));
+    }
+
+    #[test]
+    fn merge3_keeps_crlf_and_missing_final_newline() {
+        let merged = merge3("a\r\nb\r\nc", "A\r\nb\r\nc", "a\r\nb\r\nC", LABELS, None);
+        assert_eq!(merged, Some(("A\r\nb\r\nC".to_string(), false)));
+    }
+
+    #[test]
+    fn merge3_marks_conflicts() {
+        let (text, conflicted) = merge3("a\nb\n", "x\nb\n", "y\nb\n", LABELS, None).unwrap();
+        assert!(conflicted);
+        assert_eq!(
+            text,
+            "<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nb\n".to_string()
+        );
+    }
+
+    #[test]
+    fn merge3_conflict_markers_start_on_their_own_line() {
+        let (text, _) = merge3("a", "x", "y", LABELS, None).unwrap();
+        assert_eq!(text, "<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn merge3_strategies_resolve_conflicts() {
+        let merge = |strategy| merge3("a\nb\n", "x\nb\n", "y\nb\n", LABELS, Some(strategy));
+        assert_eq!(
+            merge(MergeStrategy::Ours),
+            Some(("x\nb\n".to_string(), false))
+        );
+        assert_eq!(
+            merge(MergeStrategy::Theirs),
+            Some(("y\nb\n".to_string(), false))
+        );
+        assert_eq!(
+            merge(MergeStrategy::Union),
+            Some(("x\ny\nb\n".to_string(), false))
+        );
+    }
+}
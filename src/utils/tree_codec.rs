@@ -0,0 +1,357 @@
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+};
+
+use super::{Errors, TreeStruct, entry_meta::EntryMeta, escape::escape_path};
+
+/// Version written into new trees.
+pub const TREE_FORMAT: u8 = 2;
+
+/// Starts every tree from format 2 on and is followed by the format byte.
+/// Format 1 trees have no header, they start with the high byte of a mode, which
+/// is never `D`.
+const TREE_MAGIC: &[u8; 4] = b"DNLT";
+
+/// Set in the serialised mode of a tree entry that is followed by a metadata
+/// record. Parsed entries never carry it.
+const META_FLAG: u32 = 0x0100_0000;
+
+/// Set next to `META_FLAG` when the metadata record is followed by a hardlink
+/// record.
+const LINK_FLAG: u32 = 0x0200_0000;
+
+/// Encodes a tree in the current format: the header, then for every entry its
+/// mode (4), name length (4), name, hash (32) and the optional records, each
+/// prefixed with its length (4). Big endian.
+pub fn encode_tree(entries: &[TreeStruct]) -> Result<Vec<u8>, Errors> {
+    let mut content = Vec::new();
+    content.extend_from_slice(TREE_MAGIC);
+    content.push(TREE_FORMAT);
+
+    for entry in entries {
+        let name = entry.name.as_bytes();
+        check_name(name)?;
+
+        let mut mode = u32::from_be_bytes(entry.mode);
+        if let Some(meta) = &entry.meta {
+            mode |= META_FLAG;
+            if meta.link.is_some() {
+                mode |= LINK_FLAG;
+            }
+        }
+
+        content.extend_from_slice(&mode.to_be_bytes());
+        push_sized(&mut content, name);
+        content.extend_from_slice(&entry.hash);
+
+        if let Some(meta) = &entry.meta {
+            let mut record = Vec::new();
+            meta.encode(&mut record);
+            push_sized(&mut content, &record);
+
+            if let Some(link) = &meta.link {
+                check_link(link)?;
+                push_sized(&mut content, link.as_os_str().as_bytes());
+            }
+        }
+    }
+
+    Ok(content)
+}
+
+/// Decodes a tree of any format up to `TREE_FORMAT`. Never panics on malformed
+/// input.
+pub fn parse_tree(tree: &[u8]) -> Result<Vec<TreeStruct>, Errors> {
+    let mut reader = Reader::new(tree);
+
+    let version = if tree.starts_with(TREE_MAGIC) {
+        reader.take(TREE_MAGIC.len());
+        reader.take(1).ok_or(Errors::MalformedTree)?[0]
+    } else {
+        1
+    };
+    if version > TREE_FORMAT {
+        return Err(Errors::FormatTooNew("tree", version.into()));
+    }
+
+    let mut entries = Vec::new();
+    while !reader.is_empty() {
+        let entry = match version {
+            1 => read_entry_v1(&mut reader),
+            _ => read_entry_v2(&mut reader),
+        };
+        entries.push(entry.ok_or(Errors::MalformedTree)??);
+    }
+
+    Ok(entries)
+}
+
+/// Format 1 entry: mode (4), space, name, NUL, hash (32), optional records.
+fn read_entry_v1(reader: &mut Reader) -> Option<Result<TreeStruct, Errors>> {
+    let mode = reader.u32()?;
+    if reader.take(1)? != b" " {
+        return None;
+    }
+    let name = reader.until(0)?;
+    let hash = reader.take(32)?.try_into().ok()?;
+    Some(finish_entry(reader, mode, name, hash))
+}
+
+/// Format 2 entry, see `encode_tree`.
+fn read_entry_v2(reader: &mut Reader) -> Option<Result<TreeStruct, Errors>> {
+    let mode = reader.u32()?;
+    let name = reader.sized()?;
+    let hash = reader.take(32)?.try_into().ok()?;
+    Some(finish_entry(reader, mode, name, hash))
+}
+
+fn finish_entry(
+    reader: &mut Reader,
+    mode: u32,
+    name: &[u8],
+    hash: [u8; 32],
+) -> Result<TreeStruct, Errors> {
+    check_name(name)?;
+
+    let meta = if mode & META_FLAG != 0 {
+        let mut meta = reader
+            .sized()
+            .and_then(EntryMeta::decode)
+            .ok_or(Errors::MalformedTree)?;
+        if mode & LINK_FLAG != 0 {
+            let link = reader.sized().ok_or(Errors::MalformedTree)?;
+            let link = PathBuf::from(OsString::from_vec(link.to_vec()));
+            check_link(&link)?;
+            meta.link = Some(link);
+        }
+        Some(meta)
+    } else {
+        None
+    };
+
+    Ok(TreeStruct {
+        mode: (mode & !(META_FLAG | LINK_FLAG)).to_be_bytes(),
+        name: OsString::from_vec(name.to_vec()),
+        hash,
+        meta,
+    })
+}
+
+/// Names are stored as raw bytes. They can not be empty, `.` or `..`, nor contain
+/// a NUL or a `/`, which would let an entry point outside its directory.
+pub fn check_name(name: &[u8]) -> Result<(), Errors> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&0) || name.contains(&b'/')
+    {
+        return Err(Errors::InvalidName(escape_path(Path::new(
+            OsStr::from_bytes(name),
+        ))));
+    }
+    Ok(())
+}
+
+/// Hardlink targets are relative to the root of the saved tree and made of
+/// plain names only, so restoring one can not reach a file outside of it.
+pub fn check_link(link: &Path) -> Result<(), Errors> {
+    if link.as_os_str().is_empty()
+        || !link
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(Errors::InvalidLink(escape_path(link)));
+    }
+    Ok(())
+}
+
+fn push_sized(content: &mut Vec<u8>, data: &[u8]) {
+    content.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    content.extend_from_slice(data);
+}
+
+/// Bounds-checked cursor over an encoded tree or record.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    /// Bytes prefixed with their length (4).
+    pub fn sized(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Bytes up to `end`, which is skipped.
+    fn until(&mut self, end: u8) -> Option<&'a [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|b| *b == end)?;
+        let bytes = self.take(len)?;
+        self.pos += 1;
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mode: u32, name: &str, meta: Option<EntryMeta>) -> TreeStruct {
+        TreeStruct {
+            mode: mode.to_be_bytes(),
+            name: OsString::from(name),
+            hash: *blake3::hash(name.as_bytes()).as_bytes(),
+            meta,
+        }
+    }
+
+    fn meta(link: Option<&str>) -> EntryMeta {
+        EntryMeta {
+            mtime: -1_700_000_000,
+            mtime_nsec: 999_999_999,
+            uid: 1000,
+            gid: u32::MAX,
+            xattrs: vec![(b"user.tag".to_vec(), vec![0, 1, 2])],
+            link: link.map(PathBuf::from),
+        }
+    }
+
+    fn sample() -> Vec<TreeStruct> {
+        vec![
+            entry(0o100644, "plain.txt", None),
+            entry(0o040755, "dir", Some(meta(None))),
+            entry(0o100755, "second link", Some(meta(Some("sub/first")))),
+        ]
+    }
+
+    fn assert_same(parsed: &[TreeStruct], expected: &[TreeStruct]) {
+        assert_eq!(parsed.len(), expected.len());
+        for (a, b) in parsed.iter().zip(expected) {
+            assert_eq!(
+                (&a.mode, &a.name, &a.hash, &a.meta),
+                (&b.mode, &b.name, &b.hash, &b.meta)
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_meta_and_links() {
+        let entries = sample();
+        let encoded = encode_tree(&entries).unwrap();
+        assert!(encoded.starts_with(TREE_MAGIC));
+        assert_eq!(encoded[TREE_MAGIC.len()], TREE_FORMAT);
+
+        let parsed = parse_tree(&encoded).unwrap();
+        assert_same(&parsed, &entries);
+        for parsed in &parsed {
+            assert_eq!(u32::from_be_bytes(parsed.mode) & (META_FLAG | LINK_FLAG), 0);
+        }
+        assert_same(&parse_tree(&encode_tree(&[]).unwrap()).unwrap(), &[]);
+    }
+
+    #[test]
+    fn format_1_trees_still_parse() {
+        let plain = entry(0o100644, "old.txt", None);
+        let mut tree = 0o100644u32.to_be_bytes().to_vec();
+        tree.push(b' ');
+        tree.extend_from_slice(b"old.txt\0");
+        tree.extend_from_slice(&plain.hash);
+
+        assert_same(&parse_tree(&tree).unwrap(), &[plain]);
+    }
+
+    #[test]
+    fn truncated_trees_are_rejected() {
+        let entries = sample();
+        let encoded = encode_tree(&entries).unwrap();
+        let boundaries: Vec<usize> = (0..=entries.len())
+            .map(|n| encode_tree(&entries[..n]).unwrap().len())
+            .collect();
+
+        for cut in TREE_MAGIC.len()..encoded.len() {
+            let parsed = parse_tree(&encoded[..cut]);
+            if boundaries.contains(&cut) {
+                assert!(parsed.is_ok(), "cut at {cut}");
+            } else {
+                assert!(matches!(parsed, Err(Errors::MalformedTree)), "cut at {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_trees_are_rejected() {
+        let mut newer = encode_tree(&sample()).unwrap();
+        newer[TREE_MAGIC.len()] = TREE_FORMAT + 1;
+        assert!(matches!(
+            parse_tree(&newer),
+            Err(Errors::FormatTooNew("tree", _))
+        ));
+
+        let mut garbage_meta = TREE_MAGIC.to_vec();
+        garbage_meta.push(TREE_FORMAT);
+        garbage_meta.extend_from_slice(&(0o100644 | META_FLAG).to_be_bytes());
+        push_sized(&mut garbage_meta, b"name");
+        garbage_meta.extend_from_slice(&[0; 32]);
+        push_sized(&mut garbage_meta, b"not a record");
+        assert!(matches!(
+            parse_tree(&garbage_meta),
+            Err(Errors::MalformedTree)
+        ));
+
+        for name in ["", ".", "..", "a/b", "nul\0"] {
+            let bad = [entry(0o100644, name, None)];
+            assert!(matches!(encode_tree(&bad), Err(Errors::InvalidName(_))));
+
+            let mut tree = TREE_MAGIC.to_vec();
+            tree.push(TREE_FORMAT);
+            tree.extend_from_slice(&0o100644u32.to_be_bytes());
+            push_sized(&mut tree, name.as_bytes());
+            tree.extend_from_slice(&[0; 32]);
+            assert!(matches!(parse_tree(&tree), Err(Errors::InvalidName(_))));
+        }
+    }
+
+    #[test]
+    fn links_leaving_the_tree_are_rejected() {
+        for link in ["../first", "/etc/passwd", "sub/../../first", "./first"] {
+            let bad = [entry(0o100644, "second", Some(meta(Some(link))))];
+            assert!(matches!(encode_tree(&bad), Err(Errors::InvalidLink(_))));
+
+            // Encode a harmless link of the same length and swap the bytes in.
+            let stand_in = "z".repeat(link.len());
+            let good = [entry(0o100644, "second", Some(meta(Some(&stand_in))))];
+            let mut tree = encode_tree(&good).unwrap();
+            let at = tree
+                .windows(link.len())
+                .position(|w| w == stand_in.as_bytes())
+                .unwrap();
+            tree[at..at + link.len()].copy_from_slice(link.as_bytes());
+            assert!(matches!(parse_tree(&tree), Err(Errors::InvalidLink(_))));
+        }
+    }
+}